@@ -0,0 +1,243 @@
+use std::net::SocketAddr;
+
+use cgmath::Vector2;
+use common::{
+    logger::warn,
+    network::{
+        lan::{LanAnnouncement, LanDiscovery},
+        proto::PROTOCOL_VERSION,
+    },
+};
+use graphics::{
+    color::Color3,
+    ctx::Frame,
+    text::{HorizontalAlign, Layout, Section, Text, VerticalAlign},
+};
+use winit::keyboard::KeyCode;
+
+use crate::core::{
+    platform::PlatformInput,
+    status::{ServerInfo, StatusQuery},
+};
+
+enum EntryStatus {
+    Querying(StatusQuery),
+    Online(ServerInfo),
+    Offline(String),
+}
+
+struct ServerEntry {
+    address: String,
+    status: EntryStatus,
+}
+
+impl ServerEntry {
+    fn query(address: String) -> Self {
+        let status = match StatusQuery::start(&address) {
+            Ok(query) => EntryStatus::Querying(query),
+            Err(e) => EntryStatus::Offline(e.to_string()),
+        };
+        Self { address, status }
+    }
+}
+
+/// Lists known servers with their status, followed by the games announced on the
+/// local network. The selected one is joined with enter.
+pub struct ServerBrowser {
+    entries: Vec<ServerEntry>,
+    lan: Option<LanDiscovery>,
+    lan_games: Vec<(SocketAddr, LanAnnouncement)>,
+    selected: usize,
+}
+
+impl ServerBrowser {
+    pub fn new(addresses: impl IntoIterator<Item = String>) -> Self {
+        let lan = LanDiscovery::listen()
+            .inspect_err(|e| warn!("Failed to listen for LAN games: {e}"))
+            .ok();
+
+        Self {
+            entries: addresses.into_iter().map(ServerEntry::query).collect(),
+            lan,
+            lan_games: Vec::new(),
+            selected: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len() + self.lan_games.len()
+    }
+
+    fn selected_address(&self) -> Option<String> {
+        match self.entries.get(self.selected) {
+            Some(entry) => Some(entry.address.clone()),
+            None => self
+                .lan_games
+                .get(self.selected - self.entries.len())
+                .map(|(address, _)| address.to_string()),
+        }
+    }
+
+    /// Queries every server again.
+    pub fn refresh(&mut self) {
+        for entry in &mut self.entries {
+            *entry = ServerEntry::query(std::mem::take(&mut entry.address));
+        }
+    }
+
+    pub fn update(&mut self) {
+        for entry in &mut self.entries {
+            if let EntryStatus::Querying(query) = &mut entry.status {
+                match query.poll() {
+                    Some(Ok(info)) => entry.status = EntryStatus::Online(info),
+                    Some(Err(e)) => entry.status = EntryStatus::Offline(e),
+                    None => {}
+                }
+            }
+        }
+
+        if let Some(lan) = &mut self.lan {
+            lan.poll();
+            self.lan_games = lan
+                .games()
+                .map(|(address, announcement)| (address, announcement.clone()))
+                .collect();
+            self.lan_games.sort_by_key(|(address, _)| *address);
+        }
+        self.selected = self.selected.min(self.len().saturating_sub(1));
+    }
+
+    /// Returns the address of the server to join, if one was chosen.
+    pub fn input(&mut self, event: &PlatformInput) -> Option<String> {
+        let PlatformInput::Keyboard { key, state } = event else {
+            return None;
+        };
+        if !state.is_pressed() {
+            return None;
+        }
+
+        match key {
+            KeyCode::ArrowUp => self.selected = self.selected.saturating_sub(1),
+            KeyCode::ArrowDown => {
+                self.selected = (self.selected + 1).min(self.len().saturating_sub(1))
+            }
+            KeyCode::KeyR => self.refresh(),
+            KeyCode::Enter => return self.selected_address(),
+            _ => {}
+        }
+        None
+    }
+
+    pub fn render(&self, frame: &mut Frame, window_size: impl Into<(u32, u32)>) {
+        let (w, _) = window_size.into();
+
+        let mut section = Section::default().add_text(
+            Text::new("Servers\n\n")
+                .with_color(Color3::WHITE)
+                .with_scale(48.),
+        );
+
+        let lines = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let marker = if i == self.selected { "> " } else { "  " };
+                let (details, color) = match &entry.status {
+                    EntryStatus::Querying(_) => ("Querying...".to_string(), Color3::gray(0.6)),
+                    EntryStatus::Offline(reason) => (format!("Offline: {reason}"), Color3::RED),
+                    EntryStatus::Online(ServerInfo { status, latency }) => {
+                        let mut details = format!(
+                            "{} - {}/{} players - {}ms\n{}",
+                            status.name,
+                            status.online_players,
+                            status.max_players,
+                            latency.as_millis(),
+                            status.motd
+                        );
+                        if !status.players.is_empty() {
+                            details += &format!("\nPlaying: {}", status.players.join(", "));
+                        }
+
+                        if status.protocol_version == PROTOCOL_VERSION {
+                            (details, Color3::GREEN)
+                        } else {
+                            details += &format!(
+                                "\nIncompatible version {}, this client is on version {PROTOCOL_VERSION}",
+                                status.protocol_version
+                            );
+                            (details, Color3::YELLOW)
+                        }
+                    }
+                };
+                (format!("{marker}{}\n", entry.address), format!("{details}\n\n"), color)
+            })
+            .collect::<Vec<_>>();
+
+        let lan_lines = self
+            .lan_games
+            .iter()
+            .enumerate()
+            .map(|(i, (address, game))| {
+                let marker = if self.entries.len() + i == self.selected {
+                    "> "
+                } else {
+                    "  "
+                };
+                let mut details = format!(
+                    "{} - {}/{} players",
+                    game.name, game.online_players, game.max_players
+                );
+                let color = if game.protocol_version == PROTOCOL_VERSION {
+                    Color3::GREEN
+                } else {
+                    details += &format!("\nIncompatible version {}", game.protocol_version);
+                    Color3::YELLOW
+                };
+                (
+                    format!("{marker}{address}\n"),
+                    format!("{details}\n\n"),
+                    color,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let lan_header = match (&self.lan, lan_lines.is_empty()) {
+            (None, _) => "LAN games\nCannot listen for LAN games\n\n",
+            (Some(_), true) => "LAN games\nSearching...\n\n",
+            (Some(_), false) => "LAN games\n\n",
+        };
+
+        for (address, details, color) in &lines {
+            section = section
+                .add_text(Text::new(address).with_color(Color3::WHITE).with_scale(28.))
+                .add_text(Text::new(details).with_color(*color).with_scale(20.));
+        }
+        section = section.add_text(
+            Text::new(lan_header)
+                .with_color(Color3::WHITE)
+                .with_scale(32.),
+        );
+        for (address, details, color) in &lan_lines {
+            section = section
+                .add_text(Text::new(address).with_color(Color3::WHITE).with_scale(28.))
+                .add_text(Text::new(details).with_color(*color).with_scale(20.));
+        }
+
+        frame.renderer.text.draw_section(
+            section
+                .add_text(
+                    Text::new("Up/Down to select, Enter to join, R to refresh")
+                        .with_color(Color3::WHITE)
+                        .with_scale(20.),
+                )
+                .with_layout(
+                    Layout::default()
+                        .h_align(HorizontalAlign::Center)
+                        .v_align(VerticalAlign::Top),
+                )
+                .with_screen_position(Vector2::new(w as f32 / 2., 40.))
+                .to_owned(),
+        )
+    }
+}
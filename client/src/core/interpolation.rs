@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+
+use ecs::{Entities, Entity, EntityHandle, Query};
+
+use common::core::{spatial::Position, tick::Tick};
+
+use super::tick_clock::TickClock;
+
+/// How far in the past remote entities are rendered unless configured otherwise, in seconds.
+pub const DEFAULT_INTERPOLATION_DELAY: f64 = 0.1;
+/// How long an entity keeps moving past its last snapshot when updates are late, in seconds.
+pub const MAX_EXTRAPOLATION: f64 = 0.25;
+/// Snapshots kept per entity, older ones are no longer needed once the delay has passed.
+const MAX_SNAPSHOTS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct Snapshot {
+    time: f64,
+    pos: Position,
+}
+
+/// Positions of a remote entity stamped with the server time of their tick.
+#[derive(Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+    /// Last snapshot dropped, giving the velocity to extrapolate with
+    previous: Option<Snapshot>,
+}
+
+impl SnapshotBuffer {
+    /// Snapshots older than the last one are ignored.
+    pub fn push(&mut self, time: f64, pos: Position) {
+        if self.snapshots.back().is_some_and(|last| last.time >= time) {
+            return;
+        }
+
+        if self.snapshots.len() == MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Snapshot { time, pos });
+    }
+
+    /// Position at `time`, interpolated between the surrounding snapshots,
+    /// or extrapolated for a bounded time past the last one.
+    pub fn sample(&mut self, time: f64) -> Option<Position> {
+        // Keep one snapshot before `time` to interpolate from
+        while self.snapshots.get(1).is_some_and(|next| next.time <= time) {
+            self.previous = self.snapshots.pop_front();
+        }
+
+        let first = *self.snapshots.front()?;
+        match self.snapshots.get(1) {
+            Some(next) if time >= first.time => {
+                let t = (time - first.time) / (next.time - first.time);
+                Some(Position(
+                    first.pos.0 + (next.pos.0 - first.pos.0) * t as f32,
+                ))
+            }
+            Some(_) => Some(first.pos),
+            None => Some(self.extrapolate(first, time)),
+        }
+    }
+
+    /// Continues the last known velocity, `last` being the only snapshot left.
+    fn extrapolate(&self, last: Snapshot, time: f64) -> Position {
+        let Some(previous) = self.previous else {
+            return last.pos;
+        };
+
+        let elapsed = (time - last.time).clamp(0., MAX_EXTRAPOLATION);
+        let velocity = (last.pos.0 - previous.pos.0) / (last.time - previous.time) as f32;
+        Position(last.pos.0 + velocity * elapsed as f32)
+    }
+}
+
+/// Renders remote entities `delay` seconds in the past, so there is usually
+/// a snapshot on each side of the rendered time.
+pub struct Interpolation {
+    pub delay: f64,
+}
+
+impl Interpolation {
+    pub fn new(delay: f64) -> Self {
+        Self { delay }
+    }
+
+    /// Buffers the position an entity had at `tick`.
+    pub fn push(&mut self, entity: &mut EntityHandle, tick: Tick, pos: Position) {
+        if entity.get::<SnapshotBuffer>().is_none() {
+            entity.set(SnapshotBuffer::default());
+        }
+        entity
+            .get_mut::<SnapshotBuffer>()
+            .unwrap()
+            .push(tick.as_secs(), pos);
+    }
+
+    /// Moves every entity with snapshots to where it was at the rendered time.
+    pub fn update(&self, entities: &mut Entities, clock: &TickClock) {
+        let Some(now) = clock.server_time() else {
+            return;
+        };
+        let time = now - self.delay;
+
+        for id in entities
+            .with::<SnapshotBuffer>()
+            .iter()
+            .map(|e| e.id())
+            .collect::<Vec<_>>()
+        {
+            let mut entity = entities.edit(id).unwrap();
+            let pos = entity.get_mut::<SnapshotBuffer>().unwrap().sample(time);
+            if let Some(pos) = pos {
+                entity.set(pos);
+            }
+        }
+    }
+}
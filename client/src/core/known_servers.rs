@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use common::{logger::warn, network::crypto::PublicKeyBytes};
+
+pub const KNOWN_SERVERS_PATH: &str = "known_servers.json";
+
+/// Server identity keys trusted on first use, by server address.
+pub struct KnownServers {
+    servers: HashMap<String, String>,
+}
+
+impl KnownServers {
+    pub fn load() -> Self {
+        let servers = match std::fs::read_to_string(KNOWN_SERVERS_PATH) {
+            Ok(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                warn!("Failed to parse {KNOWN_SERVERS_PATH}, no server is trusted: {e}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self { servers }
+    }
+
+    /// Addresses of the servers connected to before.
+    pub fn addresses(&self) -> impl Iterator<Item = &str> + '_ {
+        self.servers.keys().map(String::as_str)
+    }
+
+    /// Whether a key was pinned for this server, which must then always encrypt sessions.
+    pub fn is_pinned(&self, address: &str) -> bool {
+        self.servers.contains_key(address)
+    }
+
+    /// Pins the key of a server seen for the first time,
+    /// fails if the server presented another key before.
+    pub fn verify(&mut self, address: &str, key: &PublicKeyBytes) -> Result<(), String> {
+        let key = key.iter().map(|b| format!("{b:02x}")).collect::<String>();
+
+        match self.servers.get(address) {
+            Some(pinned) if *pinned == key => Ok(()),
+            Some(pinned) => Err(format!(
+                "The identity of {address} changed from {pinned} to {key}, remove it from {KNOWN_SERVERS_PATH} if this is expected"
+            )),
+            None => {
+                self.servers.insert(address.to_string(), key);
+                self.save();
+                Ok(())
+            }
+        }
+    }
+
+    fn save(&self) {
+        let raw = serde_json::to_string_pretty(&self.servers).expect("Failed to serialize");
+        std::fs::write(KNOWN_SERVERS_PATH, raw)
+            .unwrap_or_else(|e| warn!("Failed to save {KNOWN_SERVERS_PATH}: {e}"));
+    }
+}
@@ -1,6 +1,10 @@
 pub mod assets;
 pub mod camera;
+pub mod interpolation;
+pub mod known_servers;
 pub mod network;
 pub mod platform;
 pub mod rendering;
+pub mod status;
+pub mod tick_clock;
 pub mod tilemap;
@@ -1,73 +1,265 @@
 use std::{
-    io::{ErrorKind, Write},
-    net::{TcpStream, ToSocketAddrs},
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
 };
 
 use common::{
-    logger::{error, warn},
-    network::{proto::network_protocol, AnyPacket, Protocol},
+    core::tick::Tick,
+    logger::warn,
+    network::{
+        connection::{Connection, ConnectionError},
+        crypto::{self, HandshakeTranscript, KeyExchange, Role},
+        datagram::{DatagramChannel, SessionToken, MAX_DATAGRAM_SIZE},
+        proto::{
+            extra::CommonPing,
+            handshake::{EncryptionOffer, ServerboundHandshake, ServerboundKeyExchange},
+            network_protocol, PROTOCOL_VERSION,
+        },
+        stats::NetworkStats,
+        transport::{self, Transport},
+        AnyPacket, Protocol, ServerboundPacket,
+    },
 };
-use serde::Serialize;
+
+use super::tick_clock::TickClock;
+
+/// How often the client sends a datagram when it has nothing else to send,
+/// so the server learns its address and the route stays open.
+const DATAGRAM_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the server is pinged, to keep the tick clock in sync and the session alive.
+const PING_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The server the client plays on.
+pub enum ServerTarget {
+    /// A server reached over tcp at this address
+    Remote(String),
+    /// A server started by the client on a background thread
+    Singleplayer,
+}
 
 pub struct NetworkClient {
+    address: String,
     protocol: Protocol,
-    socket: TcpStream,
+    connection: Connection,
+    datagrams: Option<DatagramLink>,
+    clock: TickClock,
+    last_ping: Option<Instant>,
+}
+
+struct DatagramLink {
+    socket: UdpSocket,
+    channel: DatagramChannel,
+    last_sent: Option<Instant>,
 }
 
 impl NetworkClient {
-    pub fn connect_to(address: impl ToSocketAddrs) -> Self {
-        let socket = TcpStream::connect(address).expect("Invalid address");
-        socket
-            .set_nonblocking(true)
-            .expect("Failed to set socket to non-blocking");
+    pub fn connect_to(address: &str) -> io::Result<Self> {
+        let socket = transport::connect_tcp(address)?;
+        Ok(Self::new(Box::new(socket), address))
+    }
 
+    /// A client over an already connected transport,
+    /// `address` names the server when pinning its identity.
+    pub fn new(transport: Box<dyn Transport>, address: &str) -> Self {
         Self {
-            socket,
+            address: address.to_string(),
+            connection: Connection::new(transport),
             protocol: network_protocol(),
+            datagrams: None,
+            clock: TickClock::new(),
+            last_ping: None,
         }
     }
 
-    pub fn handle_packets(&mut self, mut handler: impl FnMut(&mut Self, AnyPacket)) {
-        loop {
-            match self.protocol.decode(&self.socket) {
-                Ok(packet) => {
-                    handler(self, packet);
-                }
-                Err(common::network::ErrorKind::Io(e))
-                    if [ErrorKind::WouldBlock].contains(&e.kind()) =>
-                {
-                    break;
-                }
-                Err(common::network::ErrorKind::Io(e))
-                    if [
-                        ErrorKind::UnexpectedEof,
-                        ErrorKind::ConnectionReset,
-                        ErrorKind::ConnectionAborted,
-                        ErrorKind::ConnectionRefused,
-                    ]
-                    .contains(&e.kind()) =>
-                {
-                    error!("Client disconnected: {}", self.socket.peer_addr().unwrap());
-                    panic!("");
-                }
-                Err(e) => {
-                    warn!("Failed to decode packet from client: {e:?}");
-                    continue;
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn handshake(&mut self) {
+        let fingerprint = self.protocol.fingerprint();
+        self.send(&ServerboundHandshake {
+            protocol_version: PROTOCOL_VERSION,
+            fingerprint,
+        });
+    }
+
+    /// Opens the datagram channel of the session, on the same address as the stream.
+    /// Does nothing if the transport cannot carry datagrams.
+    pub fn open_datagram_channel(&mut self, token: SessionToken) -> io::Result<()> {
+        let Some(server) = self.connection.datagram_addr() else {
+            return Ok(());
+        };
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0; 8], 0).into(),
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        socket.connect(server)?;
+
+        self.datagrams = Some(DatagramLink {
+            socket,
+            channel: DatagramChannel::new(token, self.connection.datagram_cipher()),
+            last_sent: None,
+        });
+        Ok(())
+    }
+
+    /// Receives every available packet, packets decoded before an error are still pushed.
+    /// Invalid datagrams are dropped rather than failing the connection.
+    pub fn poll_packets(&mut self, packets: &mut Vec<AnyPacket>) -> Result<(), ConnectionError> {
+        let result = self.connection.receive(&self.protocol, packets);
+
+        if let Some(DatagramLink {
+            socket, channel, ..
+        }) = &mut self.datagrams
+        {
+            let mut buffer = [0; MAX_DATAGRAM_SIZE];
+            loop {
+                match socket.recv(&mut buffer) {
+                    Ok(size) => {
+                        if let Err(e) = channel.receive(&self.protocol, &buffer[..size], packets) {
+                            warn!("Dropped datagram from server: {e}");
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    // Reported by some platforms when a previous datagram was not delivered
+                    Err(e) if e.kind() == ErrorKind::ConnectionRefused => continue,
+                    Err(e) => {
+                        warn!("Failed to receive datagrams: {e}");
+                        break;
+                    }
                 }
             }
         }
+
+        result
+    }
+
+    /// Pings the server if it was not pinged recently.
+    pub fn ping(&mut self) {
+        if self
+            .last_ping
+            .is_some_and(|last| last.elapsed() < PING_INTERVAL)
+        {
+            return;
+        }
+        self.last_ping = Some(Instant::now());
+
+        let tick = self.clock.tick().unwrap_or_default();
+        self.send(&CommonPing {
+            time: Instant::now(),
+            tick,
+        });
+    }
+
+    /// Syncs the tick clock to the tick a ping was answered with.
+    pub fn ping_answered(&mut self, tick: Tick, sent: Instant) {
+        self.clock.synchronize(tick, sent.elapsed());
     }
 
-    pub fn send<T: Serialize + 'static>(&mut self, packet: &T) {
-        let data = self.protocol.encode(packet);
-        self.socket
-            .write_all(&data)
-            .unwrap_or_else(|e| warn!("Failed to write packet to client: {e:?}"));
+    pub fn clock(&self) -> &TickClock {
+        &self.clock
+    }
+
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    pub fn set_compression_threshold(&mut self, threshold: Option<u32>) {
+        self.connection.set_compression_threshold(threshold);
+    }
+
+    /// Answers the server's key exchange, every following frame is encrypted.
+    /// The compression threshold must already be the one the server sent.
+    pub fn start_encryption(&mut self, offer: &EncryptionOffer) -> Result<(), String> {
+        let transcript = HandshakeTranscript {
+            protocol_version: PROTOCOL_VERSION,
+            fingerprint: self.protocol.fingerprint(),
+            compression_threshold: self.connection.compression_threshold(),
+        };
+        if !crypto::verify_exchange(
+            &offer.identity_key,
+            &transcript,
+            &offer.ephemeral_key,
+            &offer.signature,
+        ) {
+            return Err("The handshake was not signed by the server identity".to_string());
+        }
+
+        let exchange = KeyExchange::new();
+        self.send(&ServerboundKeyExchange {
+            ephemeral_key: exchange.public_key(),
+        });
+
+        let session = exchange
+            .finish(&offer.ephemeral_key, Role::Client)
+            .ok_or("Invalid key exchange public key")?;
+        self.connection.start_session(session);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        let mut stats = *self.connection.stats();
+        if let Some(link) = &self.datagrams {
+            stats.merge(link.channel.stats());
+        }
+        stats
+    }
+
+    pub fn send<T: ServerboundPacket>(&mut self, packet: &T) {
+        if let Some(link) = self
+            .datagrams
+            .as_mut()
+            .filter(|_| !T::DELIVERY.is_reliable())
+        {
+            let encoded = self.protocol.encode_packet(packet);
+            if DatagramChannel::can_carry(&encoded) {
+                link.channel.send(&encoded);
+                return;
+            }
+        }
+
+        let frame = self
+            .protocol
+            .encode(packet, self.connection.compression_threshold());
+        self.connection.send(&frame);
     }
 
     pub fn flush(&mut self) {
-        self.socket
+        self.connection
             .flush()
             .unwrap_or_else(|e| warn!("Failed to flush socket to server: {e:?}"));
+
+        if let Some(link) = &mut self.datagrams {
+            link.flush();
+        }
+    }
+}
+
+impl DatagramLink {
+    /// Datagrams that cannot be sent right away are dropped, like lost ones.
+    fn flush(&mut self) {
+        let mut datagrams = self.channel.take_datagrams();
+        if datagrams.is_empty()
+            && self
+                .last_sent
+                .map_or(true, |last| last.elapsed() >= DATAGRAM_KEEPALIVE_INTERVAL)
+        {
+            datagrams.push(self.channel.empty_datagram());
+        }
+
+        for datagram in datagrams {
+            match self.socket.send(&datagram) {
+                Ok(_) => self.last_sent = Some(Instant::now()),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to send datagram to server: {e}");
+                    break;
+                }
+            }
+        }
     }
 }
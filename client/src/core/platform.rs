@@ -3,7 +3,7 @@ use std::sync::Arc;
 use winit::window::{Window, WindowId};
 use winit::{application::ApplicationHandler, window::WindowAttributes};
 use winit::{
-    event::{ElementState, WindowEvent},
+    event::{ElementState, MouseButton, WindowEvent},
     keyboard::KeyCode,
 };
 use winit::{
@@ -66,6 +66,9 @@ impl<T: AppLayer> ApplicationHandler for Platform<T> {
                     },
                 );
             }
+            WindowEvent::MouseInput { state, button, .. } => {
+                app.input(wid, PlatformInput::MouseButton { button, state });
+            }
             WindowEvent::MouseWheel { delta, .. } => {
                 app.input(
                     wid,
@@ -101,9 +104,22 @@ impl<T: AppLayer> ApplicationHandler for Platform<T> {
 }
 
 pub enum PlatformInput {
-    Keyboard { key: KeyCode, state: ElementState },
-    CursorMoved { x: f32, y: f32 },
-    MouseScrolled { x: f32, y: f32 },
+    Keyboard {
+        key: KeyCode,
+        state: ElementState,
+    },
+    CursorMoved {
+        x: f32,
+        y: f32,
+    },
+    MouseButton {
+        button: MouseButton,
+        state: ElementState,
+    },
+    MouseScrolled {
+        x: f32,
+        y: f32,
+    },
 }
 
 pub struct PlatformHandle<'a>(&'a ActiveEventLoop);
@@ -0,0 +1,112 @@
+use std::{
+    io,
+    net::TcpStream,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::network::{
+    proto::handshake::{ClientboundStatusResponse, ServerStatus, ServerboundStatusRequest},
+    transport,
+};
+
+use super::network::NetworkClient;
+
+/// How long a server has to answer a status query, connecting included.
+pub const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct ServerInfo {
+    pub status: ServerStatus,
+    /// Time between the query and the answer
+    pub latency: Duration,
+}
+
+enum QueryState {
+    /// Connecting on a background thread, so an unreachable server does not block the caller
+    Connecting(Receiver<io::Result<TcpStream>>),
+    Sent {
+        network: NetworkClient,
+        sent: Instant,
+    },
+}
+
+/// A status query over its own connection, which the server closes once it answered.
+pub struct StatusQuery {
+    address: String,
+    state: QueryState,
+    started: Instant,
+}
+
+impl StatusQuery {
+    pub fn start(address: &str) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let target = address.to_string();
+        thread::Builder::new()
+            .name(format!("status query to {address}"))
+            .spawn(move || {
+                // The query may have been dropped while connecting
+                let _ = sender.send(transport::connect_tcp_timeout(target, STATUS_TIMEOUT));
+            })?;
+
+        Ok(Self {
+            address: address.to_string(),
+            state: QueryState::Connecting(receiver),
+            started: Instant::now(),
+        })
+    }
+
+    /// The answer of the server, or why there is none, once the query is over.
+    pub fn poll(&mut self) -> Option<Result<ServerInfo, String>> {
+        if let QueryState::Connecting(receiver) = &self.state {
+            match receiver.try_recv() {
+                Ok(Ok(socket)) => {
+                    let mut network = NetworkClient::new(Box::new(socket), &self.address);
+                    network.send(&ServerboundStatusRequest);
+                    self.state = QueryState::Sent {
+                        network,
+                        sent: Instant::now(),
+                    };
+                }
+                Ok(Err(e)) => return Some(Err(e.to_string())),
+                Err(TryRecvError::Empty) => return self.check_timeout(),
+                Err(TryRecvError::Disconnected) => {
+                    return Some(Err("Failed to connect".to_string()))
+                }
+            }
+        }
+        let QueryState::Sent { network, sent } = &mut self.state else {
+            unreachable!("Connected above");
+        };
+
+        network.flush();
+
+        let mut packets = Vec::new();
+        let result = network.poll_packets(&mut packets);
+
+        if let Some(packet) = packets
+            .iter()
+            .find(|packet| packet.is::<ClientboundStatusResponse>())
+        {
+            return Some(
+                packet
+                    .decode::<ClientboundStatusResponse>()
+                    .map(|response| ServerInfo {
+                        status: response.status,
+                        latency: sent.elapsed(),
+                    })
+                    .map_err(|e| e.to_string()),
+            );
+        }
+
+        if let Err(e) = result {
+            return Some(Err(e.to_string()));
+        }
+        self.check_timeout()
+    }
+
+    fn check_timeout(&self) -> Option<Result<ServerInfo, String>> {
+        (self.started.elapsed() > STATUS_TIMEOUT)
+            .then(|| Err("The server did not answer".to_string()))
+    }
+}
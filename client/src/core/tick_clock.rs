@@ -0,0 +1,51 @@
+use std::time::{Duration, Instant};
+
+use common::core::tick::Tick;
+
+/// How much of the error a new ping corrects, smoothing out jitter.
+const SMOOTHING: f64 = 0.1;
+/// Errors larger than this are corrected at once, after a stall of either side.
+const MAX_DRIFT: f64 = 0.25;
+
+/// Estimates the tick the server is running, synchronized by pings.
+pub struct TickClock {
+    started: Instant,
+    /// Server time minus local time, in seconds
+    offset: Option<f64>,
+}
+
+impl TickClock {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            offset: None,
+        }
+    }
+
+    fn local_time(&self) -> f64 {
+        self.started.elapsed().as_secs_f64()
+    }
+
+    /// Takes in the tick the server answered a ping with, `rtt` after it was sent.
+    /// The server is assumed to have answered halfway through.
+    pub fn synchronize(&mut self, tick: Tick, rtt: Duration) {
+        let server_time = tick.as_secs() + rtt.as_secs_f64() / 2.;
+        let offset = server_time - self.local_time();
+
+        self.offset = Some(match self.offset {
+            Some(current) if (offset - current).abs() <= MAX_DRIFT => {
+                current + (offset - current) * SMOOTHING
+            }
+            _ => offset,
+        });
+    }
+
+    /// Seconds since the server's tick zero, once a ping was answered.
+    pub fn server_time(&self) -> Option<f64> {
+        Some(self.local_time() + self.offset?)
+    }
+
+    pub fn tick(&self) -> Option<Tick> {
+        self.server_time().map(Tick::from_secs)
+    }
+}
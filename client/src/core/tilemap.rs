@@ -1,9 +1,13 @@
 use cgmath::{Array, Matrix3, Vector2, Zero};
 use common::{
-    tilemap::{ChunkCoord, TileChunk, TileMap},
-    utils::handle::{DynamicHandle, HandleType, HandleTypeUnion, StaticHandle},
+    tilemap::{ChunkCoord, TileChunk, TileMap, CHUNK_SIZE},
+    utils::{
+        handle::{DynamicHandle, HandleType, HandleTypeUnion, StaticHandle},
+        registry::RecordId,
+    },
 };
 use graphics::{
+    color::Color3,
     ctx::Frame,
     sprite::{Sprite, SpriteDrawParams, SpriteSheetHandle},
 };
@@ -39,19 +43,46 @@ pub struct ClientTileData<T: HandleType = DynamicHandle> {
     pub sprite: Sprite<T::Handle<SpriteHandles>>,
 }
 
+/// The chunks streamed by the server, those not received yet are drawn as placeholders.
 pub struct ClientTileMap {
     common: TileMap,
     relative_selected_tile: Vector2<f32>,
+    /// Of the window, to know which chunks are visible
+    aspect_ratio: f32,
 }
 
 impl ClientTileMap {
-    pub fn new(common: TileMap) -> Self {
+    pub fn new() -> Self {
         Self {
-            common,
+            common: TileMap::new(),
             relative_selected_tile: Vector2::new(0., 0.),
+            aspect_ratio: 1.,
         }
     }
 
+    pub fn insert_chunk(&mut self, coord: ChunkCoord, chunk: TileChunk) {
+        self.common.insert(coord, chunk);
+    }
+
+    /// Returns whether the chunk of the tile was loaded.
+    pub fn set_tile(&mut self, pos: Vector2<i32>, tile: RecordId) -> bool {
+        self.common.set_tile(pos, tile)
+    }
+
+    /// Returns whether the chunk was loaded.
+    pub fn remove_chunk(&mut self, coord: ChunkCoord) -> bool {
+        self.common.unload(coord).is_some()
+    }
+
+    /// Chunks overlapping the part of the world the camera shows.
+    fn visible_chunks(&self, camera: &Camera) -> impl Iterator<Item = ChunkCoord> {
+        let extent = Vector2::new(self.aspect_ratio, 1.) / camera.zoom;
+        let min = ChunkCoord::from_position(camera.pos - extent).0;
+        let max = ChunkCoord::from_position(camera.pos + extent).0;
+        (min.x..=max.x)
+            .flat_map(move |x| (min.y..=max.y).map(move |y| ChunkCoord(Vector2::new(x, y))))
+    }
+
     pub fn render(
         &self,
         frame: &mut Frame,
@@ -59,8 +90,13 @@ impl ClientTileMap {
         camera: &Camera,
         draw_overlay: bool,
     ) {
-        for (chunk_coords, chunk) in self.common.chunks.iter() {
-            render_chunk(&chunk, frame, assets, &chunk_coords, camera)
+        for (chunk_coords, chunk) in self.common.loaded_chunks() {
+            render_chunk(chunk, frame, assets, &chunk_coords, camera)
+        }
+        for chunk_coords in self.visible_chunks(camera) {
+            if !self.common.is_loaded(chunk_coords) {
+                render_placeholder(frame, assets, &chunk_coords, camera);
+            }
         }
 
         if draw_overlay {
@@ -81,13 +117,16 @@ impl ClientTileMap {
     }
 
     pub fn input(&mut self, event: &PlatformInput, window_size: impl Into<(u32, u32)>) {
+        let (w, h) = window_size.into();
+        let aspect_ratio = w as f32 / h as f32;
+        if aspect_ratio.is_finite() && aspect_ratio > 0. {
+            self.aspect_ratio = aspect_ratio;
+        }
+
         let PlatformInput::CursorMoved { x, y } = *event else {
             return;
         };
 
-        let (w, h) = window_size.into();
-        let aspect_ratio = w as f32 / h as f32;
-
         let mut pos = Vector2::new((x as f32 * aspect_ratio) / w as f32, y as f32 / h as f32) * 2.
             - Vector2::new(1. * aspect_ratio, 1.);
         pos.y *= -1.;
@@ -128,3 +167,28 @@ fn render_chunk(
         }
     }
 }
+
+/// Covers a chunk the server has not sent yet.
+fn render_placeholder(
+    frame: &mut Frame,
+    assets: &ClientAssets,
+    chunk_coords: &ChunkCoord,
+    camera: &Camera,
+) {
+    let origin = chunk_coords.to_tile_coords().map(|i| i as f32);
+
+    frame.renderer.sprites.draw(
+        Sprite {
+            sheet: assets.textures.get_id("debug").clone(),
+            pos: Vector2::zero(),
+            size: Vector2::new(1, 1),
+        },
+        SpriteDrawParams {
+            transform: camera.view_transform()
+                * Matrix3::from_translation(origin - Vector2::from_value(0.5)) // tile corner
+                * Matrix3::from_nonuniform_scale(CHUNK_SIZE.x as f32, CHUNK_SIZE.y as f32),
+            tint: Color3::gray(0.25),
+            ..Default::default()
+        },
+    );
+}
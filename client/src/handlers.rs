@@ -0,0 +1,205 @@
+use std::cell::OnceCell;
+
+use common::{
+    logger::{error, info, warn},
+    network::{
+        proto::{
+            extra::{ClientboundDisconnect, CommonPing, DisconnectReason},
+            handshake::{ClientboundHandshakeRejected, ClientboundHandshakeSuccess},
+            login::{ClientboundLoginSuccess, ServerboundLoginStart},
+            play::{
+                ClientboundChunkData, ClientboundEntityDelta, ClientboundPlayerState,
+                ClientboundRemoveEntity, ClientboundSpawnEntity, ClientboundTileUpdate,
+                ClientboundUnloadChunk,
+            },
+        },
+        router::{ConnectionState, PacketRouter},
+    },
+};
+use ecs::Entity;
+
+use crate::{
+    core::camera::Camera,
+    load_entity_textures,
+    player::{PlayerEntityController, PlayerInventoryController},
+    state::{ClientState, Remote},
+    GameClient,
+};
+
+pub type ClientRouter = PacketRouter<GameClient>;
+
+pub fn packet_router() -> ClientRouter {
+    use ConnectionState::*;
+
+    let mut router = PacketRouter::new();
+    router
+        .on(Login, handshake_success)
+        .on(Login, handshake_rejected)
+        .on(Login, login_success)
+        .on(Play, spawn_entity)
+        .on(Play, entity_delta)
+        .on(Play, player_state)
+        .on(Play, remove_entity)
+        .on(Play, chunk_data)
+        .on(Play, unload_chunk)
+        .on(Play, tile_update)
+        .on_each(&[Login, Play], ping)
+        .on_each(&[Login, Play], disconnect);
+    router
+}
+
+fn ping(client: &mut GameClient, _: (), CommonPing { time, tick }: CommonPing) {
+    if let Some(network) = &mut client.network {
+        network.ping_answered(tick, time);
+    }
+    client
+        .window
+        .set_title(&format!("Underworld - {:?}ms", time.elapsed().as_millis()));
+}
+
+fn handshake_success(client: &mut GameClient, _: (), packet: ClientboundHandshakeSuccess) {
+    let Some(network) = &mut client.network else {
+        return;
+    };
+    network.set_compression_threshold(packet.compression_threshold);
+
+    let trusted = match &packet.encryption {
+        Some(offer) => client
+            .known_servers
+            .verify(network.address(), &offer.identity_key)
+            .and_then(|()| network.start_encryption(offer)),
+        // Otherwise a man in the middle could strip the offer to read the session
+        None if client.known_servers.is_pinned(network.address()) => Err(format!(
+            "{} offered no encryption although its identity is pinned",
+            network.address()
+        )),
+        None => Ok(()),
+    };
+    if let Err(reason) = trusted {
+        error!("Refusing to log in: {reason}");
+        client.disconnected(DisconnectReason::UntrustedServer(reason));
+        return;
+    }
+
+    network.send(&ServerboundLoginStart {
+        username: client.config.username.clone(),
+    });
+}
+
+fn handshake_rejected(client: &mut GameClient, _: (), packet: ClientboundHandshakeRejected) {
+    error!("Server rejected the connection: {}", packet.reason);
+    client.disconnected(DisconnectReason::ProtocolMismatch(packet.reason));
+}
+
+fn disconnect(client: &mut GameClient, _: (), packet: ClientboundDisconnect) {
+    client.disconnected(packet.reason);
+}
+
+fn login_success(client: &mut GameClient, _: (), packet: ClientboundLoginSuccess) {
+    let ClientboundLoginSuccess { datagram_token } = packet;
+    info!("Successfully logged in!");
+
+    if let Some(network) = &mut client.network {
+        network
+            .open_datagram_channel(datagram_token)
+            .unwrap_or_else(|e| warn!("Failed to open datagram channel, using the stream: {e}"));
+    }
+
+    client.state = ClientState::Connected {
+        player_entity: OnceCell::new(),
+        camera: Camera::new(),
+        pe_controller: PlayerEntityController::default(),
+        pi_controller: PlayerInventoryController::new(&client.assets.common.tiles),
+        remote: Remote::new(client.config.interpolation_delay),
+    };
+}
+
+fn spawn_entity(client: &mut GameClient, _: (), packet: ClientboundSpawnEntity) {
+    let ClientState::Connected {
+        player_entity,
+        remote,
+        ..
+    } = &mut client.state
+    else {
+        return;
+    };
+
+    let mut entity = remote.spawn_entity(packet.snapshot);
+    load_entity_textures(&mut entity, &client.assets);
+
+    // First spawned entity is player
+    player_entity.get_or_init(|| entity.id());
+}
+
+fn entity_delta(client: &mut GameClient, _: (), packet: ClientboundEntityDelta) {
+    let ClientState::Connected {
+        player_entity,
+        remote,
+        ..
+    } = &mut client.state
+    else {
+        return;
+    };
+
+    for snapshot in packet.entities {
+        remote.apply_delta(packet.tick, snapshot, player_entity.get().copied());
+    }
+}
+
+fn player_state(client: &mut GameClient, _: (), packet: ClientboundPlayerState) {
+    let ClientState::Connected {
+        player_entity,
+        pe_controller,
+        remote,
+        ..
+    } = &mut client.state
+    else {
+        return;
+    };
+
+    let Some(player) = player_entity
+        .get()
+        .and_then(|player| remote.entities.edit(*player))
+    else {
+        return;
+    };
+    pe_controller.reconcile(&player, packet);
+}
+
+fn remove_entity(client: &mut GameClient, _: (), packet: ClientboundRemoveEntity) {
+    let ClientState::Connected { remote, .. } = &mut client.state else {
+        return;
+    };
+
+    if !remote.remove_entity(packet.entity) {
+        warn!("Received entity despawn packet but entity was not found");
+    }
+}
+
+fn chunk_data(client: &mut GameClient, _: (), packet: ClientboundChunkData) {
+    let ClientState::Connected { remote, .. } = &mut client.state else {
+        return;
+    };
+
+    remote.terrain.insert_chunk(packet.coord, packet.chunk);
+}
+
+fn unload_chunk(client: &mut GameClient, _: (), packet: ClientboundUnloadChunk) {
+    let ClientState::Connected { remote, .. } = &mut client.state else {
+        return;
+    };
+
+    if !remote.terrain.remove_chunk(packet.coord) {
+        warn!("Received chunk unload packet but chunk was not loaded");
+    }
+}
+
+fn tile_update(client: &mut GameClient, _: (), packet: ClientboundTileUpdate) {
+    let ClientState::Connected { remote, .. } = &mut client.state else {
+        return;
+    };
+
+    if !remote.terrain.set_tile(packet.pos, packet.tile) {
+        warn!("Received tile update but its chunk was not loaded");
+    }
+}
@@ -1,24 +1,20 @@
-use std::{cell::OnceCell, sync::Arc, time::Instant};
+use std::{rc::Rc, sync::Arc};
 
+use browser::ServerBrowser;
 use cgmath::{Array, Vector2, Zero};
 use common::{
     core::EntityKind,
     logger::{info, warn},
-    network::proto::{
-        extra::{CommonPing, ServerboundDisconnect},
-        login::{ClientboundLoginSuccess, ServerboundLoginStart},
-        play::{ClientboundRemoveEntity, ClientboundSetEntityPosition, ClientboundSpawnEntity},
-        SyncComponentSelection,
-    },
+    network::proto::extra::{DisconnectReason, ServerboundDisconnect},
     utils::timer::Timer,
 };
 use core::assets::ClientAssets;
-use core::camera::Camera;
-use core::network::NetworkClient;
+use core::interpolation::DEFAULT_INTERPOLATION_DELAY;
+use core::known_servers::KnownServers;
+use core::network::{NetworkClient, ServerTarget};
 use core::platform::{AppLayer, PlatformHandle, PlatformInput};
 use core::rendering::RenderData;
-use core::tilemap::ClientTileMap;
-use ecs::{Entities, Entity, EntityHandle, Query};
+use ecs::{Entity, EntityHandle};
 use graphics::{
     color::Color3,
     sprite::{Sprite, SpriteDrawParams},
@@ -26,26 +22,32 @@ use graphics::{
     Graphics,
 };
 use gui::{inventory::PlayerInventory, GuiManager};
-use player::{PlayerEntityController, PlayerInventoryController};
-use state::{ClientState, Remote};
+use handlers::{packet_router, ClientRouter};
+use server::LocalServer;
+use state::ClientState;
 use winit::{
     keyboard::KeyCode,
     window::{Window, WindowAttributes, WindowId},
 };
 
+pub mod browser;
 pub mod core;
 pub mod gui;
+pub mod handlers;
 pub mod overlays;
 pub mod player;
 pub mod state;
 
 pub struct GameClient {
+    router: Rc<ClientRouter>,
     config: GameClientConfig,
     window: Arc<Window>,
     graphics: Graphics,
     assets: ClientAssets,
     timer: Timer,
-    network: NetworkClient,
+    network: Option<NetworkClient>,
+    known_servers: KnownServers,
+    local_server: Option<LocalServer>,
 
     gui_manager: GuiManager,
 
@@ -54,12 +56,18 @@ pub struct GameClient {
 
 pub struct GameClientConfig {
     pub username: String,
+    /// Server joined on start, the server browser opens without one
+    pub server: Option<ServerTarget>,
+    /// Seconds remote entities are rendered in the past
+    pub interpolation_delay: f64,
 }
 
 impl GameClientConfig {
     pub fn default() -> Self {
         Self {
             username: "Noobie".to_string(),
+            server: None,
+            interpolation_delay: DEFAULT_INTERPOLATION_DELAY,
         }
     }
 }
@@ -72,24 +80,26 @@ impl AppLayer for GameClient {
             platform.create_window(WindowAttributes::default().with_title("Underworld Client"));
         let assets = ClientAssets::load();
         let graphics = Graphics::new(window.inner_size(), window.clone(), assets.textures.iter());
-        let mut network = NetworkClient::connect_to("127.0.0.1:8888");
-        network.send(&ServerboundLoginStart {
-            username: config.username.clone(),
-        });
         let gui_manager = GuiManager::new();
 
-        let state = ClientState::Connecting;
-
-        Self {
+        let mut client = Self {
+            router: Rc::new(packet_router()),
             config,
             window,
             graphics,
             assets,
-            network,
+            network: None,
+            known_servers: KnownServers::load(),
+            local_server: None,
             timer,
             gui_manager,
-            state,
+            state: ClientState::Connecting,
+        };
+        match client.config.server {
+            Some(_) => client.connect(),
+            None => client.browse(),
         }
+        client
     }
 
     fn render(&mut self, _: WindowId) {
@@ -101,84 +111,95 @@ impl AppLayer for GameClient {
             self.state.render(frame, &self.assets, draw_ig_overlay);
             self.gui_manager.render_if_open(frame, &self.assets);
 
+            match &self.state {
+                ClientState::Browsing { browser } => {
+                    browser.render(frame, self.window.inner_size())
+                }
+                ClientState::Disconnected { reason } => {
+                    overlays::disconnected_overlay(frame, reason, self.window.inner_size())
+                }
+                _ => {}
+            }
+
             #[cfg(debug_assertions)]
-            overlays::debug_overlay(frame, dt);
+            {
+                let stats = self.network.as_ref().map(|n| n.stats());
+                let interpolation_delay = match &self.state {
+                    ClientState::Connected { remote, .. } => Some(remote.interpolation_delay()),
+                    _ => None,
+                };
+                overlays::debug_overlay(frame, dt, stats.as_ref(), interpolation_delay);
+            }
         });
     }
 
     fn update(&mut self) {
         let dt = self.timer.update_dt();
 
-        self.network.send(&CommonPing {
-            time: Instant::now(),
-        });
+        if let ClientState::Browsing { browser } = &mut self.state {
+            browser.update();
+        }
 
-        self.network.handle_packets(|network, packet| {
-            if let Some(CommonPing { time }) = packet.try_decode() {
-                self.window
-                    .set_title(&format!("Underworld - {:?}ms", time.elapsed().as_millis()));
-            }
+        let Some(network) = &mut self.network else {
+            return;
+        };
 
-            match &mut self.state {
-                ClientState::Connecting => {
-                    if let Some(ClientboundLoginSuccess { terrain, ecs_state }) =
-                        packet.try_decode()
-                    {
-                        info!("Successfully logged in!");
-
-                        let mut entities = Entities::load(ecs_state);
-                        load_entities_textures(&mut entities, &self.assets);
-
-                        self.state = ClientState::Connected {
-                            player_entity: OnceCell::new(),
-                            camera: Camera::new(),
-                            pe_controller: PlayerEntityController::default(),
-                            pi_controller: PlayerInventoryController::default(),
-                            remote: Remote {
-                                terrain: ClientTileMap::new(terrain),
-                                entities,
-                            },
-                        };
-                    }
-                }
-                ClientState::Connected {
-                    player_entity,
-                    remote,
-                    ..
-                } => {
-                    if let Some(ClientboundSpawnEntity { entity, state }) = packet.try_decode() {
-                        let mut entity = remote
-                            .entities
-                            .load_entity::<SyncComponentSelection>(entity, state);
-
-                        load_entity_textures(&mut entity, &self.assets);
-
-                        // First spawned entity is player
-                        player_entity.get_or_init(|| entity.id());
-                    } else if let Some(ClientboundSetEntityPosition { entity, pos }) =
-                        packet.try_decode()
-                    {
-                        let eid = entity.validate(&remote.entities);
-                        remote.sync_entity_position(eid, pos);
-                    } else if let Some(ClientboundRemoveEntity { entity }) = packet.try_decode() {
-                        if let Some(mut entity) =
-                            remote.entities.edit(entity.validate(&remote.entities))
-                        {
-                            entity.despawn();
-                        } else {
-                            warn!("Received entity despawn packet but entity was not found");
-                        }
-                    }
-                }
+        // The server only answers pings once the handshake and key exchange are done
+        if matches!(self.state, ClientState::Connected { .. }) {
+            network.ping();
+        }
+
+        let mut packets = Vec::new();
+        let result = network.poll_packets(&mut packets);
+
+        let router = self.router.clone();
+        for packet in packets {
+            let state = self.state.connection_state();
+            let name = self
+                .network
+                .as_ref()
+                .and_then(|network| network.protocol().info(packet.id))
+                .map(|info| info.name);
+            if let Err(e) = router.route(self, state, (), packet) {
+                warn!("Failed to handle {name:?} from server: {e}");
             }
-        });
+        }
+
+        if let Err(e) = result {
+            self.disconnected(DisconnectReason::ConnectionLost(e.to_string()));
+        }
 
-        self.state.update(dt, &mut self.network);
+        let Some(network) = &mut self.network else {
+            return; // Disconnected by the server
+        };
 
-        self.network.flush();
+        self.state.update(dt, network);
+
+        network.flush();
     }
 
     fn input(&mut self, _: WindowId, event: PlatformInput) {
+        if let ClientState::Browsing { browser } = &mut self.state {
+            if let Some(address) = browser.input(&event) {
+                self.config.server = Some(ServerTarget::Remote(address));
+                self.connect();
+            }
+            return;
+        }
+
+        if let (ClientState::Disconnected { .. }, PlatformInput::Keyboard { key, state }) =
+            (&self.state, &event)
+        {
+            if state.is_pressed() {
+                match key {
+                    KeyCode::KeyR => self.connect(),
+                    KeyCode::KeyB => self.browse(),
+                    _ => {}
+                }
+            }
+            return;
+        }
+
         if let PlatformInput::Keyboard {
             key: KeyCode::KeyE, ..
         } = event
@@ -191,8 +212,16 @@ impl AppLayer for GameClient {
     }
 
     fn exit(&mut self) {
-        self.network.send(&ServerboundDisconnect::GameClosed);
-        self.network.flush();
+        if let Some(network) = &mut self.network {
+            network.send(&ServerboundDisconnect::GameClosed);
+            network.flush();
+        }
+
+        // Closing the connection lets the local server stop
+        self.network = None;
+        if let Some(server) = self.local_server.take() {
+            server.stop();
+        }
     }
 
     fn window_resized(&mut self) {
@@ -204,19 +233,66 @@ impl AppLayer for GameClient {
     }
 }
 
-fn load_entities_textures(entities: &mut Entities, assets: &ClientAssets) {
-    for id in entities
-        .with::<EntityKind>()
-        .iter()
-        .map(|e| e.id())
-        .collect::<Vec<_>>()
-    {
-        load_entity_textures(&mut entities.edit(id).unwrap(), assets);
+impl GameClient {
+    fn connect(&mut self) {
+        let network = match &self.config.server {
+            None => return self.browse(),
+            Some(ServerTarget::Remote(address)) => NetworkClient::connect_to(address),
+            Some(ServerTarget::Singleplayer) => {
+                let server = self.local_server.get_or_insert_with(LocalServer::start);
+                server
+                    .connect()
+                    .map(|stream| NetworkClient::new(Box::new(stream), "singleplayer"))
+            }
+        };
+        let mut network = match network {
+            Ok(network) => network,
+            Err(e) => {
+                warn!("Failed to connect to the server: {e}");
+                self.disconnected(DisconnectReason::ConnectionLost(e.to_string()));
+                return;
+            }
+        };
+
+        // Login starts once the server accepted the handshake
+        network.handshake();
+
+        self.network = Some(network);
+        self.state = ClientState::Connecting;
+    }
+
+    /// Opens the server browser, listing every known server and the games on the local network.
+    fn browse(&mut self) {
+        let mut addresses = self
+            .known_servers
+            .addresses()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        addresses.sort();
+
+        self.network = None;
+        self.gui_manager.close();
+        self.state = ClientState::Browsing {
+            browser: ServerBrowser::new(addresses),
+        };
+    }
+
+    fn disconnected(&mut self, reason: DisconnectReason) {
+        if matches!(self.state, ClientState::Disconnected { .. }) {
+            return;
+        }
+
+        info!("Disconnected from server: {reason}");
+        self.network = None;
+        self.gui_manager.close();
+        self.state = ClientState::Disconnected { reason };
     }
 }
 
 fn load_entity_textures(entity: &mut EntityHandle, assets: &ClientAssets) {
-    let kind = entity.get::<EntityKind>().unwrap().clone();
+    let Some(kind) = entity.get::<EntityKind>().map(|kind| kind.clone()) else {
+        return;
+    };
     match kind {
         EntityKind::Player => {
             entity.set(RenderData::new().with(
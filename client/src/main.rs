@@ -1,12 +1,12 @@
-use client::{GameClient, GameClientConfig};
+use client::{core::network::ServerTarget, GameClient, GameClientConfig};
 
 fn main() {
     std::env::set_var("RUST_BACKTRACE", "1");
     let mut config = GameClientConfig::default();
 
-    let args = std::env::args();
+    let mut args = std::env::args().skip(1);
 
-    if let Some(username) = args.skip(1).next() {
+    if let Some(username) = args.next() {
         config.username = username;
     } else {
         // Ask for username in terminal
@@ -18,6 +18,13 @@ fn main() {
         config.username = buff.trim().to_string();
     }
 
+    // Either a server address or `singleplayer`, the server browser opens without one
+    config.server = match args.next().as_deref() {
+        Some("singleplayer") => Some(ServerTarget::Singleplayer),
+        Some(address) => Some(ServerTarget::Remote(address.to_string())),
+        None => None,
+    };
+
     common::logger::init_logger();
     client::core::platform::run_app::<GameClient>(config);
 }
@@ -6,22 +6,66 @@ use graphics::{
     text::{HorizontalAlign, Layout, Section, Text, VerticalAlign},
 };
 
+use common::network::{proto::extra::DisconnectReason, stats::NetworkStats};
+
 use crate::{core::assets::ClientAssets, state::ClientState};
 
-pub fn debug_overlay(frame: &mut Frame, dt: f32) {
+pub fn debug_overlay(
+    frame: &mut Frame,
+    dt: f32,
+    network: Option<&NetworkStats>,
+    interpolation_delay: Option<f64>,
+) {
+    let mut text = format!("FPS: {}", 1. / dt);
+    if let Some(stats) = network {
+        text += &format!("\n{stats}");
+    }
+    if let Some(delay) = interpolation_delay {
+        text += &format!("\nInterpolation delay: {:.0}ms", delay * 1000.);
+    }
+
+    frame.renderer.text.draw_section(
+        Section::default()
+            .add_text(Text::new(&text).with_color(Color3::WHITE).with_scale(24.))
+            .with_layout(
+                Layout::default()
+                    .h_align(HorizontalAlign::Left)
+                    .v_align(VerticalAlign::Top),
+            )
+            .with_screen_position(Vector2::new(10.0, 10.0))
+            .to_owned(),
+    )
+}
+
+pub fn disconnected_overlay(
+    frame: &mut Frame,
+    reason: &DisconnectReason,
+    window_size: impl Into<(u32, u32)>,
+) {
+    let (w, h) = window_size.into();
     frame.renderer.text.draw_section(
         Section::default()
             .add_text(
-                Text::new(&format!("FPS: {}", 1. / dt))
+                Text::new("Disconnected\n\n")
+                    .with_color(Color3::WHITE)
+                    .with_scale(48.),
+            )
+            .add_text(
+                Text::new(&format!("{reason}\n\n"))
+                    .with_color(Color3::WHITE)
+                    .with_scale(24.),
+            )
+            .add_text(
+                Text::new("Press R to reconnect, B to browse servers")
                     .with_color(Color3::WHITE)
                     .with_scale(24.),
             )
             .with_layout(
                 Layout::default()
-                    .h_align(HorizontalAlign::Left)
-                    .v_align(VerticalAlign::Top),
+                    .h_align(HorizontalAlign::Center)
+                    .v_align(VerticalAlign::Center),
             )
-            .with_screen_position(Vector2::new(10.0, 10.0))
+            .with_screen_position(Vector2::new(w as f32 / 2., h as f32 / 2.))
             .to_owned(),
     )
 }
@@ -48,7 +92,24 @@ pub fn play_overlay(frame: &mut Frame, state: &ClientState, assets: &ClientAsset
                             * Matrix3::from_translation(Vector2::new(-0.5, 0.)),
                         ..Default::default()
                     },
-                )
+                );
+
+                // Tile placed from the slot, over it
+                if let Some(tile) = pi_controller.slot_tiles.get(i as usize) {
+                    let (_, tile) = assets.tiles.lookup(*tile);
+                    frame.renderer.sprites.draw(
+                        tile.sprite,
+                        SpriteDrawParams {
+                            transform: Matrix3::from_translation(Vector2::new(
+                                -0.81 + i as f32 * 0.18,
+                                -0.925,
+                            )) * Matrix3::from_scale(0.08)
+                                * Matrix3::from_translation(Vector2::from_value(-0.5)),
+                            depth: 1.,
+                            ..Default::default()
+                        },
+                    );
+                }
             }
         }
         _ => {}
@@ -1,28 +1,38 @@
+use std::{collections::VecDeque, time::Instant};
+
 use crate::core::{network::NetworkClient, platform::PlatformInput, rendering::RenderData};
-use cgmath::{InnerSpace, Vector2, Zero};
+use cgmath::{InnerSpace, Vector2};
 use common::{
-    core::spatial::Position, network::proto::play::ServerboundSetPlayerPos, utils::maths::MaybeNan,
+    core::{
+        movement::{self, InputCommand, MovementInput, MAX_INPUT_DT, MAX_PENDING_INPUTS},
+        spatial::Position,
+        tick::TICK_DURATION,
+    },
+    network::proto::play::{
+        ClientboundPlayerState, ServerboundBreakTile, ServerboundPlayerInputs, ServerboundSetTile,
+    },
+    tilemap::tile::{Tile, MAX_REACH},
+    utils::registry::{RecordId, Registry},
 };
 use ecs::Entity;
-use winit::{event::ElementState, keyboard::KeyCode};
+use winit::{
+    event::{ElementState, MouseButton},
+    keyboard::KeyCode,
+};
 
 /// Player controller
 pub enum PlayerEntityController {
     Moving {
-        forward: bool,
-        backward: bool,
-        left: bool,
-        right: bool,
+        input: MovementInput,
+        prediction: MovementPrediction,
     },
 }
 
 impl Default for PlayerEntityController {
     fn default() -> Self {
         Self::Moving {
-            forward: false,
-            backward: false,
-            left: false,
-            right: false,
+            input: MovementInput::default(),
+            prediction: MovementPrediction::default(),
         }
     }
 }
@@ -31,10 +41,14 @@ impl PlayerEntityController {
     pub fn handle_input(&mut self, input: &PlatformInput) {
         match self {
             PlayerEntityController::Moving {
-                forward,
-                backward,
-                left,
-                right,
+                input:
+                    MovementInput {
+                        forward,
+                        backward,
+                        left,
+                        right,
+                    },
+                ..
             } => match input {
                 &PlatformInput::Keyboard { key, state } => {
                     *match key {
@@ -50,62 +64,177 @@ impl PlayerEntityController {
         }
     }
 
-    pub fn move_player(&self, entity: &impl Entity, dt: f32, network: &mut NetworkClient) {
+    /// Moves the player right away and sends the input for the server to simulate.
+    pub fn move_player(&mut self, entity: &impl Entity, dt: f32, network: &mut NetworkClient) {
         match self {
-            PlayerEntityController::Moving {
-                forward,
-                backward,
-                left,
-                right,
-            } => {
+            PlayerEntityController::Moving { input, prediction } => {
                 let mut render_data = entity.get_mut::<RenderData>().unwrap();
                 let sheet_pos = &mut render_data.sprites[0].0.pos;
 
-                let mut dir = Vector2::<f32>::zero();
-
-                if *forward {
+                if input.forward {
                     *sheet_pos = Vector2::new(0, 3);
-                    dir.y += 1.;
                 }
-                if *backward {
+                if input.backward {
                     *sheet_pos = Vector2::new(0, 0);
-                    dir.y -= 1.;
                 }
-                if *left {
+                if input.left {
                     *sheet_pos = Vector2::new(0, 2);
-                    dir.x -= 1.;
                 }
-                if *right {
+                if input.right {
                     *sheet_pos = Vector2::new(0, 1);
-                    dir.x += 1.;
                 }
 
-                let mut pos = entity.get_mut::<Position>().unwrap();
-                let speed = 1.5;
+                // One still input once the player stops, so the server tells others
+                if input.is_moving() || prediction.was_moving() {
+                    let command = prediction.record(*input, dt);
+                    let mut pos = entity.get_mut::<Position>().unwrap();
+                    movement::apply_movement(&mut *pos, command.input, command.dt);
+                }
 
-                pos.0 += (dir.normalize() * dt * speed).no_nan();
+                // Resent until acknowledged, even once the player stopped,
+                // at the server tick rate rather than every frame
+                if !prediction.pending.is_empty() && prediction.should_send() {
+                    network.send(&ServerboundPlayerInputs {
+                        inputs: prediction.pending.iter().copied().collect(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Takes the server position and replays the inputs it has not simulated yet.
+    pub fn reconcile(&mut self, entity: &impl Entity, state: ClientboundPlayerState) {
+        match self {
+            PlayerEntityController::Moving { prediction, .. } => {
+                prediction.acknowledge(state.last_input);
 
-                if *forward || *backward || *left || *right {
-                    network.send(&ServerboundSetPlayerPos { pos: *pos });
+                let mut pos = state.pos;
+                for command in &prediction.pending {
+                    movement::apply_movement(&mut pos, command.input, command.dt);
                 }
+                *entity.get_mut::<Position>().unwrap() = pos;
             }
         }
     }
 }
 
-pub struct PlayerInventoryController {
-    pub actionbar_slot: u8,
+/// Inputs applied locally before the server confirmed them.
+#[derive(Default)]
+pub struct MovementPrediction {
+    last_sequence: u32,
+    last_input: MovementInput,
+    pending: VecDeque<InputCommand>,
+    /// Last sequence number sent to the server, and when
+    sent_sequence: u32,
+    last_sent: Option<Instant>,
 }
 
-impl Default for PlayerInventoryController {
-    fn default() -> Self {
-        Self { actionbar_slot: 0 }
+impl MovementPrediction {
+    fn record(&mut self, input: MovementInput, dt: f32) -> InputCommand {
+        self.last_input = input;
+
+        // Frames between two sends share a command while the input stays the same,
+        // moving once by their summed time is the same as moving for each of them
+        let sent_sequence = self.sent_sequence;
+        if let Some(last) = self.pending.back_mut().filter(|last| {
+            last.sequence > sent_sequence && last.input == input && last.dt + dt <= MAX_INPUT_DT
+        }) {
+            last.dt += dt;
+            return InputCommand { dt, ..*last };
+        }
+
+        self.last_sequence += 1;
+        let command = InputCommand {
+            sequence: self.last_sequence,
+            input,
+            dt,
+        };
+
+        if self.pending.len() == MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(command);
+        command
+    }
+
+    /// Whether the pending inputs should be sent now, at most once per server tick.
+    fn should_send(&mut self) -> bool {
+        if self
+            .last_sent
+            .is_some_and(|last| last.elapsed() < TICK_DURATION)
+        {
+            return false;
+        }
+        self.last_sent = Some(Instant::now());
+        self.sent_sequence = self.last_sequence;
+        true
     }
+
+    fn was_moving(&self) -> bool {
+        self.last_input.is_moving()
+    }
+
+    fn acknowledge(&mut self, last_input: u32) {
+        while self
+            .pending
+            .front()
+            .is_some_and(|command| command.sequence <= last_input)
+        {
+            self.pending.pop_front();
+        }
+    }
+}
+
+/// A tile edit waiting to be sent to the server.
+#[derive(Debug, Clone, Copy)]
+pub enum TileAction {
+    Place(Vector2<i32>, RecordId),
+    Break(Vector2<i32>),
+}
+
+pub struct PlayerInventoryController {
+    pub actionbar_slot: u8,
+    /// Tile placed from each actionbar slot, in order
+    pub slot_tiles: Vec<RecordId>,
+    queued: Vec<TileAction>,
 }
 
 impl PlayerInventoryController {
-    pub fn handle_input(&mut self, input: &PlatformInput) {
+    pub fn new(tiles: &Registry<Tile>) -> Self {
+        let slot_tiles = tiles
+            .entries
+            .values()
+            .enumerate()
+            .filter(|(_, tile)| tile.placeable)
+            .map(|(id, _)| RecordId(id))
+            .take(10)
+            .collect();
+
+        Self {
+            actionbar_slot: 0,
+            slot_tiles,
+            queued: Vec::new(),
+        }
+    }
+
+    pub fn selected_tile(&self) -> Option<RecordId> {
+        self.slot_tiles.get(self.actionbar_slot as usize).copied()
+    }
+
+    /// Left click breaks the tile under the cursor, right click places the selected one.
+    pub fn handle_input(&mut self, input: &PlatformInput, cursor_tile: Vector2<i32>) {
         match input {
+            &PlatformInput::MouseButton { button, state } if state.is_pressed() => {
+                let action = match button {
+                    MouseButton::Left => TileAction::Break(cursor_tile),
+                    MouseButton::Right => match self.selected_tile() {
+                        Some(tile) => TileAction::Place(cursor_tile, tile),
+                        None => return,
+                    },
+                    _ => return,
+                };
+                self.queued.push(action);
+            }
             &PlatformInput::Keyboard { key, state } => {
                 if state.is_pressed() {
                     if let Some(slot) = [
@@ -130,4 +259,21 @@ impl PlayerInventoryController {
             _ => {}
         }
     }
+
+    /// Sends the queued edits within reach of the player, the server would reject the others.
+    pub fn send_tile_actions(&mut self, player: Vector2<f32>, network: &mut NetworkClient) {
+        for action in self.queued.drain(..) {
+            let pos = match action {
+                TileAction::Place(pos, _) | TileAction::Break(pos) => pos,
+            };
+            if (pos.map(|i| i as f32) - player).magnitude() > MAX_REACH {
+                continue;
+            }
+
+            match action {
+                TileAction::Place(pos, tile) => network.send(&ServerboundSetTile { pos, tile }),
+                TileAction::Break(pos) => network.send(&ServerboundBreakTile { pos }),
+            }
+        }
+    }
 }
@@ -1,19 +1,31 @@
-use std::cell::OnceCell;
+use std::{cell::OnceCell, collections::HashMap};
 
-use common::core::spatial::Position;
+use common::{
+    core::{spatial::Position, tick::Tick},
+    logger::warn,
+    network::{
+        proto::extra::DisconnectReason,
+        replication::{replicated_components, EntitySnapshot, NetworkEntity, ReplicatedComponents},
+        router::ConnectionState,
+    },
+};
 use ecs::{Entities, Entity, EntityHandle, EntityId};
 use graphics::ctx::Frame;
 
 use crate::{
+    browser::ServerBrowser,
     core::{
-        assets::ClientAssets, camera::Camera, network::NetworkClient, platform::PlatformInput,
-        rendering::draw_entities, tilemap::ClientTileMap,
+        assets::ClientAssets, camera::Camera, interpolation::Interpolation, network::NetworkClient,
+        platform::PlatformInput, rendering::draw_entities, tilemap::ClientTileMap,
     },
     overlays,
     player::{PlayerEntityController, PlayerInventoryController},
 };
 
 pub enum ClientState {
+    Browsing {
+        browser: ServerBrowser,
+    },
     Connecting,
     Connected {
         player_entity: OnceCell<EntityId>,
@@ -23,37 +35,69 @@ pub enum ClientState {
 
         remote: Remote,
     },
+    Disconnected {
+        reason: DisconnectReason,
+    },
 }
 
 pub struct Remote {
     pub terrain: ClientTileMap,
     pub entities: Entities,
+    pub interpolation: Interpolation,
+    replication: ReplicatedComponents,
+    /// Local entities of the replicated server entities
+    network_entities: HashMap<NetworkEntity, EntityId>,
 }
 
 impl ClientState {
+    pub fn connection_state(&self) -> ConnectionState {
+        match self {
+            ClientState::Connecting => ConnectionState::Login,
+            ClientState::Connected { .. } => ConnectionState::Play,
+            ClientState::Browsing { .. } | ClientState::Disconnected { .. } => {
+                ConnectionState::Closing
+            }
+        }
+    }
+
     pub fn update(&mut self, dt: f32, network: &mut NetworkClient) {
         match self {
-            ClientState::Connecting => {}
+            ClientState::Browsing { .. }
+            | ClientState::Connecting
+            | ClientState::Disconnected { .. } => {}
             ClientState::Connected {
                 pe_controller: controller,
+                pi_controller,
                 player_entity,
-                remote: Remote { entities, .. },
+                remote:
+                    Remote {
+                        entities,
+                        interpolation,
+                        ..
+                    },
                 ..
             } => {
                 if let Some(player) = entities.edit(*player_entity.get().unwrap()) {
                     controller.move_player(&player, dt, network);
+                    let pos = player.get::<Position>().unwrap().0;
+                    pi_controller.send_tile_actions(pos, network);
                 }
+                interpolation.update(entities, network.clock());
             }
         }
     }
 
     pub fn render(&mut self, frame: &mut Frame, assets: &ClientAssets, draw_overlay: bool) {
         match self {
-            ClientState::Connecting => {}
+            ClientState::Browsing { .. }
+            | ClientState::Connecting
+            | ClientState::Disconnected { .. } => {}
             ClientState::Connected {
                 camera,
                 player_entity,
-                remote: Remote { entities, terrain },
+                remote: Remote {
+                    entities, terrain, ..
+                },
                 ..
             } => {
                 if let Some(player) = entities.edit(*player_entity.get().unwrap()) {
@@ -72,7 +116,9 @@ impl ClientState {
 
     pub fn input(&mut self, event: &PlatformInput, window_size: impl Into<(u32, u32)>) {
         match self {
-            ClientState::Connecting => {}
+            ClientState::Browsing { .. }
+            | ClientState::Connecting
+            | ClientState::Disconnected { .. } => {}
             ClientState::Connected {
                 pe_controller,
                 pi_controller,
@@ -81,18 +127,99 @@ impl ClientState {
                 ..
             } => {
                 pe_controller.handle_input(event);
-                pi_controller.handle_input(event);
                 camera.handle_input(event);
                 terrain.input(&event, window_size);
+                pi_controller.handle_input(event, terrain.selected_tile(camera));
             }
         }
     }
 }
 
 impl Remote {
-    pub fn sync_entity_position(&mut self, entity: EntityId, pos: Position) {
-        if let Some(mut e) = self.entities.edit(entity) {
-            e.set(pos);
+    pub fn new(interpolation_delay: f64) -> Self {
+        Self {
+            terrain: ClientTileMap::new(),
+            entities: Entities::new(),
+            interpolation: Interpolation::new(interpolation_delay),
+            replication: replicated_components(),
+            network_entities: HashMap::new(),
         }
     }
+
+    /// Spawns a replicated entity with the components of its snapshot.
+    pub fn spawn_entity(&mut self, snapshot: EntitySnapshot) -> EntityHandle {
+        let id = self.entities.spawn().id();
+        if let Some(previous) = self.network_entities.insert(snapshot.entity, id) {
+            warn!("Entity {:?} was spawned twice", snapshot.entity);
+            if let Some(mut entity) = self.entities.edit(previous) {
+                entity.despawn();
+            }
+        }
+
+        let mut entity = self.entities.edit(id).unwrap();
+        for component in &snapshot.components {
+            match self.replication.decode(component) {
+                Ok(value) => value.insert_into(&mut entity),
+                Err(e) => warn!(
+                    "Failed to decode {} of entity {:?}: {e}",
+                    self.replication.name(component.component),
+                    snapshot.entity
+                ),
+            }
+        }
+        entity
+    }
+
+    /// Applies the components that changed on `tick`.
+    /// Positions are buffered for interpolation, except the `predicted` entity's
+    /// which the player controller reconciles instead.
+    pub fn apply_delta(
+        &mut self,
+        tick: Tick,
+        snapshot: EntitySnapshot,
+        predicted: Option<EntityId>,
+    ) {
+        let Some(&id) = self.network_entities.get(&snapshot.entity) else {
+            warn!("Received a delta for unknown entity {:?}", snapshot.entity);
+            return;
+        };
+        let Some(mut entity) = self.entities.edit(id) else {
+            return;
+        };
+
+        for component in &snapshot.components {
+            let value = match self.replication.decode(component) {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!(
+                        "Failed to decode {} of entity {:?}: {e}",
+                        self.replication.name(component.component),
+                        snapshot.entity
+                    );
+                    continue;
+                }
+            };
+
+            match value.downcast::<Position>() {
+                Ok(_) if predicted == Some(id) => {}
+                Ok(pos) => self.interpolation.push(&mut entity, tick, pos),
+                Err(value) => value.insert_into(&mut entity),
+            }
+        }
+    }
+
+    /// Returns whether the entity was known.
+    pub fn remove_entity(&mut self, entity: NetworkEntity) -> bool {
+        let Some(id) = self.network_entities.remove(&entity) else {
+            return false;
+        };
+        if let Some(mut entity) = self.entities.edit(id) {
+            entity.despawn();
+        }
+        true
+    }
+
+    pub fn interpolation_delay(&self) -> f64 {
+        self.interpolation.delay
+    }
 }
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 
+pub mod movement;
 pub mod spatial;
+pub mod tick;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum EntityKind {
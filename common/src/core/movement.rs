@@ -0,0 +1,66 @@
+use cgmath::{InnerSpace, Vector2, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::maths::MaybeNan;
+
+use super::spatial::Position;
+
+/// Walking speed of players, in tiles per second.
+pub const PLAYER_SPEED: f32 = 1.5;
+/// Longest step a single input may simulate, a longer frame is cut short.
+pub const MAX_INPUT_DT: f32 = 0.1;
+/// Inputs not yet acknowledged by the server are resent with every new one,
+/// this many at most.
+pub const MAX_PENDING_INPUTS: usize = 64;
+
+/// Movement keys held by a player during one frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MovementInput {
+    pub forward: bool,
+    pub backward: bool,
+    pub left: bool,
+    pub right: bool,
+}
+
+impl MovementInput {
+    pub fn is_moving(&self) -> bool {
+        self.forward || self.backward || self.left || self.right
+    }
+
+    pub fn direction(&self) -> Vector2<f32> {
+        let mut dir = Vector2::zero();
+        if self.forward {
+            dir.y += 1.;
+        }
+        if self.backward {
+            dir.y -= 1.;
+        }
+        if self.left {
+            dir.x -= 1.;
+        }
+        if self.right {
+            dir.x += 1.;
+        }
+        dir
+    }
+}
+
+/// One frame of input, numbered so the server can acknowledge it.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct InputCommand {
+    pub sequence: u32,
+    pub input: MovementInput,
+    pub dt: f32,
+}
+
+/// Moves a player for one input, run by the client to predict and by the server to simulate,
+/// so both must get the same result.
+pub fn apply_movement(pos: &mut Position, input: MovementInput, dt: f32) {
+    let dt = if dt.is_finite() {
+        dt.clamp(0., MAX_INPUT_DT)
+    } else {
+        0.
+    };
+
+    pos.0 += (input.direction().normalize() * dt * PLAYER_SPEED).no_nan();
+}
@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Server simulation steps per second.
+pub const TICK_RATE: u32 = 60;
+pub const TICK_DURATION: Duration = Duration::from_nanos(1_000_000_000 / TICK_RATE as u64);
+
+/// Number of a server simulation step, counted from when the server started.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Tick(pub u64);
+
+impl Tick {
+    pub const ZERO: Tick = Tick(0);
+
+    pub fn next(self) -> Tick {
+        Tick(self.0 + 1)
+    }
+
+    /// Seconds from tick zero to the start of this tick.
+    pub fn as_secs(self) -> f64 {
+        self.0 as f64 / TICK_RATE as f64
+    }
+
+    /// The tick running at `secs` seconds after tick zero.
+    pub fn from_secs(secs: f64) -> Tick {
+        Tick((secs.max(0.) * TICK_RATE as f64) as u64)
+    }
+}
@@ -1,3 +1,6 @@
+// Lets `macros` refer to `::common` from inside this crate too
+extern crate self as common;
+
 pub mod assets;
 pub mod core;
 pub mod network;
@@ -0,0 +1,185 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, ErrorKind, Write},
+    net::SocketAddr,
+};
+
+use super::{
+    crypto::{DatagramCipher, SessionCipher},
+    frame::{self, EncodedFrame, FrameBuffer},
+    stats::NetworkStats,
+    transport::Transport,
+    AnyPacket, DecodeError, Protocol,
+};
+
+/// Outgoing data a connection may hold before it is considered unable to keep up.
+pub const DEFAULT_MAX_SEND_BACKLOG: usize = 16 * 1024 * 1024;
+
+pub struct Connection {
+    transport: Box<dyn Transport>,
+    incoming: FrameBuffer,
+    outgoing: VecDeque<u8>,
+    compression_threshold: Option<u32>,
+    session: Option<SessionCipher>,
+    stats: NetworkStats,
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    Io(io::Error),
+    Decode(DecodeError),
+}
+
+impl ConnectionError {
+    /// Whether the error means the peer went away, as opposed to misbehaving.
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, ConnectionError::Io(e) if [
+            ErrorKind::ConnectionAborted,
+            ErrorKind::ConnectionReset,
+            ErrorKind::ConnectionRefused,
+            ErrorKind::UnexpectedEof,
+        ]
+        .contains(&e.kind()))
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Io(e) => write!(f, "{e}"),
+            ConnectionError::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> Self {
+        ConnectionError::Io(e)
+    }
+}
+
+impl From<DecodeError> for ConnectionError {
+    fn from(e: DecodeError) -> Self {
+        ConnectionError::Decode(e)
+    }
+}
+
+impl Connection {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Self {
+            transport,
+            incoming: FrameBuffer::new(),
+            outgoing: VecDeque::new(),
+            compression_threshold: None,
+            session: None,
+            stats: NetworkStats::default(),
+        }
+    }
+
+    /// Where the peer receives datagrams, if the transport can carry them.
+    pub fn datagram_addr(&self) -> Option<SocketAddr> {
+        self.transport.datagram_addr()
+    }
+
+    /// Reads all available data and pushes every complete packet into `packets`.
+    /// Packets decoded before an error are still pushed.
+    /// Decoding stops after a packet changing the framing, the rest is decoded next time.
+    pub fn receive(
+        &mut self,
+        protocol: &Protocol,
+        packets: &mut Vec<AnyPacket>,
+    ) -> Result<(), ConnectionError> {
+        let filled = self.incoming.fill(&mut *self.transport);
+
+        while let Some(body) = self.incoming.next_frame(protocol.max_frame_size())? {
+            let body = match &mut self.session {
+                Some(session) => session.open(&body)?,
+                None => body,
+            };
+
+            let packet = protocol.decode_frame(body, &mut self.stats)?;
+            let changes_framing = protocol
+                .info(packet.id)
+                .is_some_and(|info| info.changes_framing);
+            packets.push(packet);
+
+            if changes_framing {
+                return Ok(()); // Errors will show up again on the next read
+            }
+        }
+
+        match filled {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && !self.incoming.is_empty() => {
+                Err(DecodeError::Truncated.into())
+            }
+            r => r.map_err(Into::into),
+        }
+    }
+
+    /// Compression threshold for outgoing packets, as negotiated during the handshake.
+    pub fn compression_threshold(&self) -> Option<u32> {
+        self.compression_threshold
+    }
+
+    pub fn set_compression_threshold(&mut self, threshold: Option<u32>) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Encrypts every frame from now on, in both directions.
+    pub fn start_session(&mut self, session: SessionCipher) {
+        self.session = Some(session);
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Keys for the datagram channel of this session, if it is encrypted.
+    pub fn datagram_cipher(&self) -> Option<DatagramCipher> {
+        self.session.as_ref().map(SessionCipher::datagram_cipher)
+    }
+
+    pub fn stats(&self) -> &NetworkStats {
+        &self.stats
+    }
+
+    /// Queues a frame to be written by the next calls to [`Connection::flush`].
+    pub fn send(&mut self, frame: &EncodedFrame) {
+        match &mut self.session {
+            Some(session) => {
+                let sealed = frame::prefix_length(&session.seal(frame.body()));
+                self.stats
+                    .record_sent(sealed.len(), frame.uncompressed_size);
+                self.outgoing.extend(&sealed);
+            }
+            None => {
+                self.stats
+                    .record_sent(frame.data.len(), frame.uncompressed_size);
+                self.outgoing.extend(&frame.data);
+            }
+        }
+    }
+
+    /// Number of queued bytes not yet accepted by the transport.
+    pub fn pending_bytes(&self) -> usize {
+        self.outgoing.len()
+    }
+
+    /// Writes as much queued data as the transport accepts without blocking.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.transport.write(self.outgoing.as_slices().0) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.transport.flush()
+    }
+}
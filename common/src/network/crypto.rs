@@ -0,0 +1,311 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use super::DecodeError;
+
+pub type PublicKeyBytes = [u8; 32];
+
+const SIGNATURE_CONTEXT: &[u8] = b"underworld key exchange";
+
+/// Long-lived server key, signing the key exchange so clients can pin the server.
+pub struct ServerIdentity {
+    signing_key: SigningKey,
+}
+
+impl ServerIdentity {
+    /// Loads the identity at `path`, generating and saving a new one if there is none.
+    /// The key is only readable by its owner, a key others can read is refused.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let signing_key = match File::open(path) {
+            Ok(mut file) => {
+                check_private(&file)?;
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                let seed = bytes.try_into().map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid server identity key")
+                })?;
+                SigningKey::from_bytes(&seed)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let signing_key = Self::generate().signing_key;
+                create_private(path)?.write_all(&signing_key.to_bytes())?;
+                signing_key
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self { signing_key })
+    }
+
+    /// A new identity, only kept in memory.
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKeyBytes {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    pub fn sign_exchange(
+        &self,
+        transcript: &HandshakeTranscript,
+        ephemeral_key: &PublicKeyBytes,
+    ) -> Vec<u8> {
+        let message = transcript.signed_message(ephemeral_key);
+        self.signing_key.sign(&message).to_bytes().to_vec()
+    }
+}
+
+/// Handshake values exchanged in the clear, signed along with the ephemeral key
+/// so they cannot be changed on the way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTranscript {
+    pub protocol_version: u32,
+    pub fingerprint: u64,
+    pub compression_threshold: Option<u32>,
+}
+
+impl HandshakeTranscript {
+    fn signed_message(&self, ephemeral_key: &PublicKeyBytes) -> Vec<u8> {
+        let mut message = SIGNATURE_CONTEXT.to_vec();
+        message.extend_from_slice(&self.protocol_version.to_le_bytes());
+        message.extend_from_slice(&self.fingerprint.to_le_bytes());
+        match self.compression_threshold {
+            Some(threshold) => {
+                message.push(1);
+                message.extend_from_slice(&threshold.to_le_bytes());
+            }
+            None => message.push(0),
+        }
+        message.extend_from_slice(ephemeral_key);
+        message
+    }
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+#[cfg(unix)]
+fn check_private(file: &File) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = file.metadata()?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("The server identity key can be accessed by other users (mode {:o}), restrict it to its owner", mode & 0o777),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private(_: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Checks that the ephemeral key and the handshake were signed by the given server identity.
+pub fn verify_exchange(
+    identity_key: &PublicKeyBytes,
+    transcript: &HandshakeTranscript,
+    ephemeral_key: &PublicKeyBytes,
+    signature: &[u8],
+) -> bool {
+    let (Ok(identity), Ok(signature)) = (
+        VerifyingKey::from_bytes(identity_key),
+        Signature::from_slice(signature),
+    ) else {
+        return false;
+    };
+
+    let message = transcript.signed_message(ephemeral_key);
+    identity.verify(&message, &signature).is_ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// One side of an x25519 exchange, consumed once the peer key is known.
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKeyBytes,
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> PublicKeyBytes {
+        self.public
+    }
+
+    /// Derives the session keys, fails if the peer key is a low order point.
+    pub fn finish(self, peer: &PublicKeyBytes, role: Role) -> Option<SessionCipher> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer));
+        if !shared.was_contributory() {
+            return None;
+        }
+
+        let (server_key, client_key) = match role {
+            Role::Server => (&self.public, peer),
+            Role::Client => (peer, &self.public),
+        };
+        let salt = [server_key.as_slice(), client_key.as_slice()].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes());
+
+        let cipher = |info: &[u8]| {
+            let mut key = [0; 32];
+            hkdf.expand(info, &mut key)
+                .expect("32 bytes is a valid hkdf output length");
+            ChaCha20Poly1305::new(Key::from_slice(&key))
+        };
+        let clientbound = cipher(b"underworld clientbound");
+        let serverbound = cipher(b"underworld serverbound");
+        let datagram_clientbound = cipher(b"underworld datagram clientbound");
+        let datagram_serverbound = cipher(b"underworld datagram serverbound");
+
+        let (send, receive, datagram) = match role {
+            Role::Server => (
+                clientbound,
+                serverbound,
+                DatagramCipher {
+                    send: datagram_clientbound,
+                    receive: datagram_serverbound,
+                },
+            ),
+            Role::Client => (
+                serverbound,
+                clientbound,
+                DatagramCipher {
+                    send: datagram_serverbound,
+                    receive: datagram_clientbound,
+                },
+            ),
+        };
+
+        Some(SessionCipher {
+            send,
+            receive,
+            sent: 0,
+            received: 0,
+            datagram,
+        })
+    }
+}
+
+/// AEAD applied to every frame body, nonces are frame counters since TCP keeps frames ordered.
+pub struct SessionCipher {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+    sent: u64,
+    received: u64,
+    datagram: DatagramCipher,
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+impl SessionCipher {
+    pub fn seal(&mut self, body: &[u8]) -> Vec<u8> {
+        let sealed = self
+            .send
+            .encrypt(&nonce(self.sent), body)
+            .expect("Failed to encrypt frame");
+        self.sent += 1;
+        sealed
+    }
+
+    pub fn open(&mut self, body: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        let opened = self
+            .receive
+            .decrypt(&nonce(self.received), body)
+            .map_err(|_| DecodeError::Decryption)?;
+        self.received += 1;
+        Ok(opened)
+    }
+
+    /// Keys of the datagram channel, derived from the same exchange.
+    pub fn datagram_cipher(&self) -> DatagramCipher {
+        self.datagram.clone()
+    }
+}
+
+/// AEAD applied to datagrams, which may be lost or reordered,
+/// so nonces are the sequence numbers carried in their header.
+#[derive(Clone)]
+pub struct DatagramCipher {
+    send: ChaCha20Poly1305,
+    receive: ChaCha20Poly1305,
+}
+
+impl DatagramCipher {
+    /// Encrypts the payload, authenticating the header along with it.
+    pub fn seal(&self, sequence: u64, header: &[u8], payload: &[u8]) -> Vec<u8> {
+        let payload = Payload {
+            msg: payload,
+            aad: header,
+        };
+        self.send
+            .encrypt(&nonce(sequence), payload)
+            .expect("Failed to encrypt datagram")
+    }
+
+    pub fn open(
+        &self,
+        sequence: u64,
+        header: &[u8],
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, DecodeError> {
+        let sealed = Payload {
+            msg: sealed,
+            aad: header,
+        };
+        self.receive
+            .decrypt(&nonce(sequence), sealed)
+            .map_err(|_| DecodeError::Decryption)
+    }
+}
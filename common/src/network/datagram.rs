@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+
+use rand_core::{OsRng, RngCore};
+
+use super::{
+    crypto::DatagramCipher, stats::NetworkStats, AnyPacket, DecodeError, Delivery, PacketId,
+    Protocol,
+};
+
+/// Datagrams are kept under the usual path MTU so they are never fragmented.
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+
+const TOKEN_SIZE: usize = std::mem::size_of::<SessionToken>();
+const HEADER_SIZE: usize = TOKEN_SIZE + std::mem::size_of::<u64>();
+const TAG_SIZE: usize = 16;
+const LENGTH_SIZE: usize = std::mem::size_of::<u16>();
+const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER_SIZE - TAG_SIZE;
+/// Sequence numbers remembered behind the highest one received, older datagrams are dropped.
+const REPLAY_WINDOW: u64 = u64::BITS as u64;
+
+/// Binds datagrams to a stream session, handed to the client at login.
+pub type SessionToken = u64;
+
+pub fn generate_token() -> SessionToken {
+    OsRng.next_u64()
+}
+
+/// The session a datagram claims to belong to, checked once it is opened.
+pub fn read_token(datagram: &[u8]) -> Option<SessionToken> {
+    let token = datagram.get(..TOKEN_SIZE)?;
+    Some(SessionToken::from_le_bytes(token.try_into().unwrap()))
+}
+
+/// One side of the unreliable channel of a session.
+///
+/// Datagrams are a header, the session token and a sequence number,
+/// followed by packets prefixed with their u16 length, encrypted when the stream is.
+/// Sequencing is per packet id: a sequenced packet is dropped when a later datagram
+/// already carried a packet with the same id.
+pub struct DatagramChannel {
+    token: SessionToken,
+    cipher: Option<DatagramCipher>,
+    sent: u64,
+    /// Highest sequence received, with a bit for each of the ones before it that were
+    received: Option<(u64, u64)>,
+    latest: HashMap<PacketId, u64>,
+    payload: Vec<u8>,
+    outgoing: Vec<Vec<u8>>,
+    stats: NetworkStats,
+}
+
+impl DatagramChannel {
+    pub fn new(token: SessionToken, cipher: Option<DatagramCipher>) -> Self {
+        Self {
+            token,
+            cipher,
+            sent: 0,
+            received: None,
+            latest: HashMap::new(),
+            payload: Vec::new(),
+            outgoing: Vec::new(),
+            stats: NetworkStats::default(),
+        }
+    }
+
+    pub fn token(&self) -> SessionToken {
+        self.token
+    }
+
+    /// Whether an encoded packet is small enough to travel in a datagram.
+    pub fn can_carry(packet: &[u8]) -> bool {
+        packet.len() + LENGTH_SIZE <= MAX_PAYLOAD_SIZE
+    }
+
+    /// Queues an encoded packet, packets queued together share datagrams.
+    pub fn send(&mut self, packet: &[u8]) {
+        debug_assert!(Self::can_carry(packet));
+
+        if self.payload.len() + LENGTH_SIZE + packet.len() > MAX_PAYLOAD_SIZE {
+            self.seal_payload();
+        }
+
+        self.payload
+            .extend_from_slice(&(packet.len() as u16).to_le_bytes());
+        self.payload.extend_from_slice(packet);
+        self.stats
+            .record_sent(packet.len() + LENGTH_SIZE, packet.len());
+    }
+
+    /// A datagram without packets, letting the peer learn where to send its own.
+    pub fn empty_datagram(&mut self) -> Vec<u8> {
+        self.seal(&[])
+    }
+
+    /// Every datagram queued since the last call.
+    pub fn take_datagrams(&mut self) -> Vec<Vec<u8>> {
+        if !self.payload.is_empty() {
+            self.seal_payload();
+        }
+        std::mem::take(&mut self.outgoing)
+    }
+
+    fn seal_payload(&mut self) {
+        let payload = std::mem::take(&mut self.payload);
+        let datagram = self.seal(&payload);
+        self.outgoing.push(datagram);
+    }
+
+    fn seal(&mut self, payload: &[u8]) -> Vec<u8> {
+        let sequence = self.sent;
+        self.sent += 1;
+
+        let mut datagram = Vec::with_capacity(HEADER_SIZE + payload.len() + TAG_SIZE);
+        datagram.extend_from_slice(&self.token.to_le_bytes());
+        datagram.extend_from_slice(&sequence.to_le_bytes());
+        match &self.cipher {
+            Some(cipher) => {
+                let sealed = cipher.seal(sequence, &datagram, payload);
+                datagram.extend_from_slice(&sealed);
+            }
+            None => datagram.extend_from_slice(payload),
+        }
+        datagram
+    }
+
+    /// Whether a datagram with this sequence number was not received before.
+    fn is_new(&self, sequence: u64) -> bool {
+        match self.received {
+            None => true,
+            Some((highest, _)) if sequence > highest => true,
+            Some((highest, window)) => {
+                let age = highest - sequence;
+                age < REPLAY_WINDOW && window & (1 << age) == 0
+            }
+        }
+    }
+
+    fn mark_received(&mut self, sequence: u64) {
+        self.received = Some(match self.received {
+            None => (sequence, 1),
+            Some((highest, window)) if sequence > highest => {
+                let shift = sequence - highest;
+                let window = if shift < REPLAY_WINDOW {
+                    window << shift
+                } else {
+                    0
+                };
+                (sequence, window | 1)
+            }
+            Some((highest, window)) => (highest, window | 1 << (highest - sequence)),
+        });
+    }
+
+    /// Pushes the packets of a datagram into `packets`, leaving out stale sequenced ones.
+    /// Nothing is pushed if the datagram is invalid or was already received.
+    /// Returns whether it is the newest datagram received, only then may the peer
+    /// be assumed to have moved to the address it came from.
+    pub fn receive(
+        &mut self,
+        protocol: &Protocol,
+        datagram: &[u8],
+        packets: &mut Vec<AnyPacket>,
+    ) -> Result<bool, DecodeError> {
+        let Some(header) = datagram.get(..HEADER_SIZE) else {
+            return Err(DecodeError::Truncated);
+        };
+        if read_token(datagram) != Some(self.token) {
+            return Err(DecodeError::UnknownSession);
+        }
+
+        let sequence = u64::from_le_bytes(header[TOKEN_SIZE..].try_into().unwrap());
+        let payload = match &self.cipher {
+            Some(cipher) => cipher.open(sequence, header, &datagram[HEADER_SIZE..])?,
+            None => datagram[HEADER_SIZE..].to_vec(),
+        };
+        // Only once authenticated, a forged sequence number must not poison the window
+        if !self.is_new(sequence) {
+            return Err(DecodeError::Replayed);
+        }
+
+        let mut received = Vec::new();
+        let mut rest = payload.as_slice();
+        while !rest.is_empty() {
+            let Some(length) = rest.get(..LENGTH_SIZE) else {
+                return Err(DecodeError::Truncated);
+            };
+            let length = u16::from_le_bytes(length.try_into().unwrap()) as usize;
+            let Some(packet) = rest.get(LENGTH_SIZE..LENGTH_SIZE + length) else {
+                return Err(DecodeError::Truncated);
+            };
+            rest = &rest[LENGTH_SIZE + length..];
+
+            let packet = protocol.decode_packet(packet)?;
+            let delivery = protocol.info(packet.id).map(|info| info.delivery);
+            if delivery.is_none_or(Delivery::is_reliable) {
+                return Err(DecodeError::WrongChannel(packet.id));
+            }
+            received.push((packet, delivery, length));
+        }
+
+        let newest = self.received.is_none_or(|(highest, _)| sequence > highest);
+        self.mark_received(sequence);
+
+        for (packet, delivery, length) in received {
+            self.stats.record_received(length + LENGTH_SIZE, length);
+
+            if delivery == Some(Delivery::UnreliableSequenced) {
+                if self
+                    .latest
+                    .get(&packet.id)
+                    .is_some_and(|latest| sequence <= *latest)
+                {
+                    continue; // A newer one was already handled
+                }
+                self.latest.insert(packet.id, sequence);
+            }
+
+            packets.push(packet);
+        }
+
+        Ok(newest)
+    }
+
+    pub fn stats(&self) -> &NetworkStats {
+        &self.stats
+    }
+}
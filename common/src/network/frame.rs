@@ -0,0 +1,273 @@
+use std::io::{self, ErrorKind, Read};
+
+use super::DecodeError;
+
+const FRAME_HEADER_SIZE: usize = std::mem::size_of::<u32>();
+const READ_CHUNK_SIZE: usize = 4096;
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// A frame ready to be written, with the size of the packet it holds before compression.
+pub struct EncodedFrame {
+    pub data: Vec<u8>,
+    pub uncompressed_size: usize,
+}
+
+impl EncodedFrame {
+    /// The frame without its length prefix.
+    pub fn body(&self) -> &[u8] {
+        &self.data[FRAME_HEADER_SIZE..]
+    }
+}
+
+/// Prefixes a frame body with its length.
+pub fn prefix_length(body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(FRAME_HEADER_SIZE + body.len());
+    data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    data.extend_from_slice(body);
+    data
+}
+
+/// Frame bodies start with the uncompressed size of the packet, or 0 when it is stored as is.
+/// Packets smaller than the threshold, or all of them without one, are stored as is.
+pub fn write_frame(packet: &[u8], compression_threshold: Option<u32>) -> EncodedFrame {
+    let compressed = compression_threshold
+        .filter(|threshold| packet.len() >= *threshold as usize)
+        .map(|_| miniz_oxide::deflate::compress_to_vec(packet, COMPRESSION_LEVEL));
+
+    let (size_prefix, body) = match &compressed {
+        Some(compressed) => (packet.len() as u32, compressed.as_slice()),
+        None => (0, packet),
+    };
+
+    let body_size = FRAME_HEADER_SIZE + body.len();
+    let mut data = Vec::with_capacity(FRAME_HEADER_SIZE + body_size);
+    data.extend_from_slice(&(body_size as u32).to_le_bytes());
+    data.extend_from_slice(&size_prefix.to_le_bytes());
+    data.extend_from_slice(body);
+
+    EncodedFrame {
+        data,
+        uncompressed_size: packet.len(),
+    }
+}
+
+/// Returns the packet held by a frame body, inflating it if needed.
+pub fn read_frame(body: Vec<u8>, max_size: u32) -> Result<Vec<u8>, DecodeError> {
+    let Some(size_prefix) = body.get(..FRAME_HEADER_SIZE) else {
+        return Err(DecodeError::Truncated);
+    };
+
+    let size = u32::from_le_bytes(size_prefix.try_into().unwrap());
+    if size == 0 {
+        return Ok(body[FRAME_HEADER_SIZE..].to_vec());
+    }
+
+    if size > max_size {
+        return Err(DecodeError::Oversize {
+            size,
+            max: max_size,
+        });
+    }
+
+    let packet = miniz_oxide::inflate::decompress_to_vec_with_limit(
+        &body[FRAME_HEADER_SIZE..],
+        size as usize,
+    )
+    .map_err(|e| DecodeError::Decompression(e.to_string()))?;
+
+    if packet.len() != size as usize {
+        return Err(DecodeError::Truncated);
+    }
+
+    Ok(packet)
+}
+
+/// Accumulates bytes read from a non-blocking stream until whole frames are available.
+#[derive(Default)]
+pub struct FrameBuffer {
+    data: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    /// Reads everything the stream currently has to offer.
+    /// Returns an `UnexpectedEof` error once the peer has closed the stream.
+    pub fn fill(&mut self, mut stream: impl Read) -> io::Result<()> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.data.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn next_frame(&mut self, max_size: u32) -> Result<Option<Vec<u8>>, DecodeError> {
+        let Some(header) = self.data.get(..FRAME_HEADER_SIZE) else {
+            return Ok(None);
+        };
+
+        let size = u32::from_le_bytes(header.try_into().unwrap());
+        if size > max_size {
+            return Err(DecodeError::Oversize {
+                size,
+                max: max_size,
+            });
+        }
+
+        let end = FRAME_HEADER_SIZE + size as usize;
+        if self.data.len() < end {
+            return Ok(None);
+        }
+
+        let frame = self.data[FRAME_HEADER_SIZE..end].to_vec();
+        self.data.drain(..end);
+        Ok(Some(frame))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    const MAX_SIZE: u32 = 1024;
+
+    /// A non-blocking stream handing out one chunk per read, then `WouldBlock`.
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(chunk) = self.0.pop_front() else {
+                return Err(ErrorKind::WouldBlock.into());
+            };
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    fn fill(buffer: &mut FrameBuffer, chunks: &[&[u8]]) {
+        let chunks = chunks.iter().map(|chunk| chunk.to_vec()).collect();
+        buffer.fill(Chunks(chunks)).unwrap();
+    }
+
+    #[test]
+    fn reads_several_frames_from_one_read() {
+        let data = [prefix_length(b"first"), prefix_length(b"second")].concat();
+        let mut buffer = FrameBuffer::new();
+        fill(&mut buffer, &[&data]);
+
+        assert_eq!(buffer.next_frame(MAX_SIZE).unwrap().unwrap(), b"first");
+        assert_eq!(buffer.next_frame(MAX_SIZE).unwrap().unwrap(), b"second");
+        assert!(buffer.next_frame(MAX_SIZE).unwrap().is_none());
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn waits_for_split_frames() {
+        let data = prefix_length(b"split frame");
+        let mut buffer = FrameBuffer::new();
+
+        // Within the length prefix, then within the body
+        fill(&mut buffer, &[&data[..2]]);
+        assert!(buffer.next_frame(MAX_SIZE).unwrap().is_none());
+        fill(&mut buffer, &[&data[2..7]]);
+        assert!(buffer.next_frame(MAX_SIZE).unwrap().is_none());
+
+        fill(&mut buffer, &[&data[7..]]);
+        assert_eq!(
+            buffer.next_frame(MAX_SIZE).unwrap().unwrap(),
+            b"split frame"
+        );
+    }
+
+    #[test]
+    fn rejects_oversize_length_prefix() {
+        let mut buffer = FrameBuffer::new();
+        fill(&mut buffer, &[&(MAX_SIZE + 1).to_le_bytes()]);
+
+        assert!(matches!(
+            buffer.next_frame(MAX_SIZE),
+            Err(DecodeError::Oversize { size, max: MAX_SIZE }) if size == MAX_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn reports_closed_stream() {
+        let mut buffer = FrameBuffer::new();
+        let error = buffer.fill(&b"\x08\0\0\0trunc"[..]).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        assert!(buffer.next_frame(MAX_SIZE).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_body() {
+        assert!(matches!(
+            read_frame(vec![0, 0], MAX_SIZE),
+            Err(DecodeError::Truncated)
+        ));
+    }
+
+    fn round_trip(packet: &[u8], compression_threshold: Option<u32>) -> Vec<u8> {
+        let frame = write_frame(packet, compression_threshold);
+        read_frame(frame.body().to_vec(), MAX_SIZE).unwrap()
+    }
+
+    #[test]
+    fn stores_packets_below_threshold() {
+        let packet = b"small packet";
+        let frame = write_frame(packet, Some(64));
+
+        assert_eq!(frame.body()[..FRAME_HEADER_SIZE], 0u32.to_le_bytes());
+        assert_eq!(round_trip(packet, Some(64)), packet);
+        assert_eq!(round_trip(packet, None), packet);
+    }
+
+    #[test]
+    fn compresses_packets_above_threshold() {
+        let packet = vec![7; 512];
+        let frame = write_frame(&packet, Some(64));
+
+        assert_eq!(frame.body()[..FRAME_HEADER_SIZE], 512u32.to_le_bytes());
+        assert!(frame.data.len() < packet.len());
+        assert_eq!(frame.uncompressed_size, packet.len());
+        assert_eq!(round_trip(&packet, Some(64)), packet);
+    }
+
+    #[test]
+    fn rejects_corrupt_deflate_stream() {
+        let packet = vec![7; 512];
+        let mut body = write_frame(&packet, Some(64)).body().to_vec();
+        body.truncate(FRAME_HEADER_SIZE + 2);
+        body.extend_from_slice(&[0xff; 8]);
+
+        assert!(matches!(
+            read_frame(body, MAX_SIZE),
+            Err(DecodeError::Decompression(_) | DecodeError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn rejects_oversize_uncompressed_size() {
+        let packet = vec![7; 512];
+        let mut body = write_frame(&packet, Some(64)).body().to_vec();
+        body[..FRAME_HEADER_SIZE].copy_from_slice(&(MAX_SIZE + 1).to_le_bytes());
+
+        assert!(matches!(
+            read_frame(body, MAX_SIZE),
+            Err(DecodeError::Oversize { .. })
+        ));
+    }
+}
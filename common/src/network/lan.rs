@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::logger::warn;
+
+/// Port servers broadcast their announcements to.
+pub const LAN_DISCOVERY_PORT: u16 = 8887;
+/// How often a server announces itself.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_millis(1500);
+/// Games not announced for this long are considered gone.
+pub const LAN_GAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Leads every announcement, other datagrams on the port are ignored.
+const MAGIC: &[u8] = b"UWLAN";
+const MAX_ANNOUNCEMENT_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LanAnnouncement {
+    pub name: String,
+    /// Port of the server's stream listener, on the address the announcement came from
+    pub port: u16,
+    pub protocol_version: u32,
+    pub online_players: u32,
+    pub max_players: u32,
+}
+
+/// Periodically sends the server's announcement to the discovery port.
+pub struct LanAnnouncer {
+    socket: UdpSocket,
+    target: SocketAddr,
+    last_sent: Option<Instant>,
+}
+
+impl LanAnnouncer {
+    /// Announces to every host of the local network.
+    pub fn broadcast() -> io::Result<Self> {
+        Self::new((Ipv4Addr::BROADCAST, LAN_DISCOVERY_PORT).into())
+    }
+
+    pub fn new(target: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            target,
+            last_sent: None,
+        })
+    }
+
+    /// Sends the announcement if the last one is older than [`ANNOUNCE_INTERVAL`].
+    pub fn announce(&mut self, announcement: &LanAnnouncement) {
+        if self
+            .last_sent
+            .is_some_and(|last| last.elapsed() < ANNOUNCE_INTERVAL)
+        {
+            return;
+        }
+        self.last_sent = Some(Instant::now());
+
+        let mut datagram = MAGIC.to_vec();
+        bincode::serialize_into(&mut datagram, announcement)
+            .expect("Failed to serialize announcement");
+        match self.socket.send_to(&datagram, self.target) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => warn!("Failed to announce the server on {}: {e}", self.target),
+        }
+    }
+}
+
+/// Servers announcing themselves on the local network.
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    games: HashMap<SocketAddr, (LanAnnouncement, Instant)>,
+}
+
+impl LanDiscovery {
+    /// Listens on the discovery port of every interface.
+    pub fn listen() -> io::Result<Self> {
+        Self::bind((Ipv4Addr::UNSPECIFIED, LAN_DISCOVERY_PORT).into())
+    }
+
+    pub fn bind(address: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            games: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Receives pending announcements and forgets games that stopped announcing.
+    pub fn poll(&mut self) {
+        let mut buffer = [0; MAX_ANNOUNCEMENT_SIZE];
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    warn!("Failed to receive LAN announcements: {e}");
+                    break;
+                }
+            };
+
+            let Some(announcement) = buffer[..size]
+                .strip_prefix(MAGIC)
+                .and_then(|data| bincode::deserialize::<LanAnnouncement>(data).ok())
+            else {
+                continue;
+            };
+
+            let server = SocketAddr::new(from.ip(), announcement.port);
+            self.games.insert(server, (announcement, Instant::now()));
+        }
+
+        self.forget_games(Instant::now());
+    }
+
+    /// Forgets games not announced for [`LAN_GAME_TIMEOUT`] as of `now`.
+    fn forget_games(&mut self, now: Instant) {
+        self.games
+            .retain(|_, (_, received)| now.duration_since(*received) < LAN_GAME_TIMEOUT);
+    }
+
+    /// Games announced recently, by the address to connect to.
+    pub fn games(&self) -> impl Iterator<Item = (SocketAddr, &LanAnnouncement)> + '_ {
+        self.games
+            .iter()
+            .map(|(address, (announcement, _))| (*address, announcement))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn announcement() -> LanAnnouncement {
+        LanAnnouncement {
+            name: "Test server".to_string(),
+            port: 8888,
+            protocol_version: 1,
+            online_players: 2,
+            max_players: 16,
+        }
+    }
+
+    /// Polls until a game is discovered or a second passed.
+    fn poll_for_games(discovery: &mut LanDiscovery) {
+        let started = Instant::now();
+        while discovery.games().next().is_none() && started.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(10));
+            discovery.poll();
+        }
+    }
+
+    #[test]
+    fn discovers_announced_game() {
+        let mut discovery = LanDiscovery::bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let mut announcer = LanAnnouncer::new(discovery.local_addr().unwrap()).unwrap();
+
+        announcer.announce(&announcement());
+        poll_for_games(&mut discovery);
+
+        let games = discovery.games().collect::<Vec<_>>();
+        assert_eq!(games.len(), 1);
+        let (address, game) = games[0];
+        assert_eq!(address, (Ipv4Addr::LOCALHOST, 8888).into());
+        assert_eq!(game.name, "Test server");
+        assert_eq!(game.online_players, 2);
+    }
+
+    #[test]
+    fn forgets_games_that_stop_announcing() {
+        let mut discovery = LanDiscovery::bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let mut announcer = LanAnnouncer::new(discovery.local_addr().unwrap()).unwrap();
+
+        announcer.announce(&announcement());
+        poll_for_games(&mut discovery);
+        assert_eq!(discovery.games().count(), 1);
+
+        discovery.forget_games(Instant::now() + LAN_GAME_TIMEOUT / 2);
+        assert_eq!(discovery.games().count(), 1);
+
+        discovery.forget_games(Instant::now() + LAN_GAME_TIMEOUT);
+        assert!(discovery.games().next().is_none());
+    }
+
+    #[test]
+    fn ignores_datagrams_without_magic() {
+        let mut discovery = LanDiscovery::bind((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+
+        let datagram = bincode::serialize(&announcement()).unwrap();
+        socket
+            .send_to(&datagram, discovery.local_addr().unwrap())
+            .unwrap();
+        poll_for_games(&mut discovery);
+
+        assert!(discovery.games().next().is_none());
+    }
+}
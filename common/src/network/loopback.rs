@@ -0,0 +1,348 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    net::SocketAddr,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+};
+
+use super::transport::{Transport, TransportListener};
+
+/// One end of an in-memory stream, the other end may live on another thread.
+pub struct LoopbackStream {
+    incoming: Receiver<Vec<u8>>,
+    outgoing: Sender<Vec<u8>>,
+    pending: VecDeque<u8>,
+}
+
+/// Two connected ends of an in-memory stream.
+pub fn pair() -> (LoopbackStream, LoopbackStream) {
+    let (a_sender, a_receiver) = mpsc::channel();
+    let (b_sender, b_receiver) = mpsc::channel();
+
+    let a = LoopbackStream {
+        incoming: a_receiver,
+        outgoing: b_sender,
+        pending: VecDeque::new(),
+    };
+    let b = LoopbackStream {
+        incoming: b_receiver,
+        outgoing: a_sender,
+        pending: VecDeque::new(),
+    };
+    (a, b)
+}
+
+impl Read for LoopbackStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.incoming.try_recv() {
+                Ok(data) => self.pending.extend(data),
+                Err(TryRecvError::Empty) => return Err(ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let read = self.pending.len().min(buf.len());
+        for (byte, pending) in buf.iter_mut().zip(self.pending.drain(..read)) {
+            *byte = pending;
+        }
+        Ok(read)
+    }
+}
+
+impl Write for LoopbackStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0); // An empty message would read as the end of the stream
+        }
+
+        self.outgoing
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for LoopbackStream {
+    fn datagram_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Creates an in-memory listener and the handle connecting to it.
+/// The listener closes once every connector is dropped.
+pub fn listener() -> (LoopbackConnector, LoopbackListener) {
+    let (sender, receiver) = mpsc::channel();
+    (
+        LoopbackConnector { listener: sender },
+        LoopbackListener {
+            incoming: receiver,
+            accepted: 0,
+            closed: false,
+        },
+    )
+}
+
+#[derive(Clone)]
+pub struct LoopbackConnector {
+    listener: Sender<LoopbackStream>,
+}
+
+impl LoopbackConnector {
+    pub fn connect(&self) -> io::Result<LoopbackStream> {
+        let (client, server) = pair();
+        self.listener
+            .send(server)
+            .map_err(|_| io::Error::from(ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+}
+
+pub struct LoopbackListener {
+    incoming: Receiver<LoopbackStream>,
+    accepted: u16,
+    closed: bool,
+}
+
+impl TransportListener for LoopbackListener {
+    /// Peers are told apart by port on the unspecified address, which no network peer can have.
+    fn accept(&mut self) -> io::Result<Option<(Box<dyn Transport>, SocketAddr)>> {
+        match self.incoming.try_recv() {
+            Ok(stream) => {
+                self.accepted = self.accepted.wrapping_add(1);
+                let addr = SocketAddr::from(([0, 0, 0, 0], self.accepted));
+                Ok(Some((Box::new(stream), addr)))
+            }
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                Ok(None)
+            }
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::{
+        core::tick::Tick,
+        network::{
+            connection::Connection,
+            crypto::{self, HandshakeTranscript, KeyExchange, Role, ServerIdentity},
+            proto::{
+                extra::CommonPing,
+                handshake::{
+                    ClientboundHandshakeSuccess, EncryptionOffer, ServerboundHandshake,
+                    ServerboundKeyExchange,
+                },
+                login::{ClientboundLoginSuccess, ServerboundLoginStart},
+                network_protocol, PROTOCOL_VERSION,
+            },
+            router::{ConnectionState, PacketRouter},
+            Packet, Protocol, DEFAULT_COMPRESSION_THRESHOLD,
+        },
+    };
+
+    use super::*;
+
+    /// One side of a session, driven by hand so every step is deterministic.
+    struct Peer {
+        protocol: Protocol,
+        connection: Connection,
+        state: ConnectionState,
+        exchange: Option<KeyExchange>,
+        received: Vec<&'static str>,
+    }
+
+    impl Peer {
+        fn new(transport: Box<dyn Transport>, state: ConnectionState) -> Self {
+            Self {
+                protocol: network_protocol(),
+                connection: Connection::new(transport),
+                state,
+                exchange: None,
+                received: Vec::new(),
+            }
+        }
+
+        fn send<T: Packet>(&mut self, packet: &T) {
+            let frame = self
+                .protocol
+                .encode(packet, self.connection.compression_threshold());
+            self.connection.send(&frame);
+        }
+
+        fn transcript(&self) -> HandshakeTranscript {
+            HandshakeTranscript {
+                protocol_version: PROTOCOL_VERSION,
+                fingerprint: self.protocol.fingerprint(),
+                compression_threshold: self.connection.compression_threshold(),
+            }
+        }
+
+        /// Sends what was queued, then handles what the other side sent.
+        fn step(&mut self, router: &PacketRouter<Peer>) {
+            self.connection.flush().unwrap();
+
+            let mut packets = Vec::new();
+            self.connection
+                .receive(&self.protocol, &mut packets)
+                .unwrap();
+            for packet in packets {
+                router.route(self, self.state, (), packet).unwrap();
+            }
+        }
+    }
+
+    fn server_router(identity: ServerIdentity) -> PacketRouter<Peer> {
+        use ConnectionState::*;
+
+        let mut router = PacketRouter::new();
+        router
+            .on(
+                Handshake,
+                move |server: &mut Peer, (), packet: ServerboundHandshake| {
+                    assert_eq!(packet.fingerprint, server.protocol.fingerprint());
+                    let exchange = KeyExchange::new();
+                    let compression_threshold = Some(DEFAULT_COMPRESSION_THRESHOLD);
+                    let transcript = HandshakeTranscript {
+                        compression_threshold,
+                        ..server.transcript()
+                    };
+                    server.send(&ClientboundHandshakeSuccess {
+                        compression_threshold,
+                        encryption: Some(EncryptionOffer {
+                            identity_key: identity.public_key(),
+                            ephemeral_key: exchange.public_key(),
+                            signature: identity.sign_exchange(&transcript, &exchange.public_key()),
+                        }),
+                    });
+                    server
+                        .connection
+                        .set_compression_threshold(compression_threshold);
+                    server.exchange = Some(exchange);
+                },
+            )
+            .on(
+                Handshake,
+                |server: &mut Peer, (), packet: ServerboundKeyExchange| {
+                    let session = server
+                        .exchange
+                        .take()
+                        .unwrap()
+                        .finish(&packet.ephemeral_key, Role::Server)
+                        .unwrap();
+                    server.connection.start_session(session);
+                    server.state = Login;
+                },
+            )
+            .on(
+                Login,
+                |server: &mut Peer, (), packet: ServerboundLoginStart| {
+                    assert_eq!(packet.username, "player");
+                    server.send(&ClientboundLoginSuccess { datagram_token: 7 });
+                    server.state = Play;
+                },
+            )
+            .on(Play, |server: &mut Peer, (), packet: CommonPing| {
+                server.received.push("ping");
+                server.send(&CommonPing {
+                    tick: Tick(packet.tick.0 + 1),
+                    ..packet
+                });
+            });
+        router
+    }
+
+    fn client_router() -> PacketRouter<Peer> {
+        use ConnectionState::*;
+
+        let mut router = PacketRouter::new();
+        router
+            .on(
+                Login,
+                |client: &mut Peer, (), packet: ClientboundHandshakeSuccess| {
+                    client
+                        .connection
+                        .set_compression_threshold(packet.compression_threshold);
+                    let offer = packet.encryption.unwrap();
+                    assert!(crypto::verify_exchange(
+                        &offer.identity_key,
+                        &client.transcript(),
+                        &offer.ephemeral_key,
+                        &offer.signature
+                    ));
+
+                    let exchange = KeyExchange::new();
+                    client.send(&ServerboundKeyExchange {
+                        ephemeral_key: exchange.public_key(),
+                    });
+                    let session = exchange.finish(&offer.ephemeral_key, Role::Client).unwrap();
+                    client.connection.start_session(session);
+                    client.send(&ServerboundLoginStart {
+                        username: "player".to_string(),
+                    });
+                },
+            )
+            .on(
+                Login,
+                |client: &mut Peer, (), packet: ClientboundLoginSuccess| {
+                    assert_eq!(packet.datagram_token, 7);
+                    client.state = Play;
+                },
+            )
+            .on(Play, |client: &mut Peer, (), packet: CommonPing| {
+                assert_eq!(packet.tick, Tick(2));
+                client.received.push("ping");
+            });
+        router
+    }
+
+    #[test]
+    fn handshake_login_and_play_over_loopback() {
+        use ConnectionState::*;
+
+        let (connector, mut listener) = listener();
+        let mut client = Peer::new(Box::new(connector.connect().unwrap()), Login);
+        let (transport, _) = listener.accept().unwrap().unwrap();
+        let mut server = Peer::new(transport, Handshake);
+        let client_router = client_router();
+        let server_router = server_router(ServerIdentity::generate());
+
+        // Packets queued while handling a step leave on the next one
+        let exchange = |client: &mut Peer, server: &mut Peer, rounds: usize| {
+            for _ in 0..rounds {
+                client.step(&client_router);
+                server.step(&server_router);
+            }
+        };
+
+        let fingerprint = client.protocol.fingerprint();
+        client.send(&ServerboundHandshake {
+            protocol_version: PROTOCOL_VERSION,
+            fingerprint,
+        });
+        exchange(&mut client, &mut server, 8);
+        assert_eq!(server.state, Play);
+        assert_eq!(client.state, Play);
+        assert!(client.connection.is_encrypted() && server.connection.is_encrypted());
+
+        client.send(&CommonPing {
+            time: Instant::now(),
+            tick: Tick(1),
+        });
+        exchange(&mut client, &mut server, 3);
+        assert_eq!(server.received, ["ping"]);
+        assert_eq!(client.received, ["ping"]);
+    }
+}
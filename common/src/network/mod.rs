@@ -1,10 +1,35 @@
-use bimap::BiMap;
 use serde::{Deserialize, Serialize};
-use std::{any::TypeId, io::Read, u16};
+use std::{collections::BTreeMap, fmt, u16};
 
 use serde::de::DeserializeOwned;
 
+pub use macros::Packet;
+
+pub mod connection;
+pub mod crypto;
+pub mod datagram;
+pub mod frame;
+pub mod lan;
+pub mod loopback;
 pub mod proto;
+pub mod replication;
+pub mod router;
+pub mod stats;
+pub mod transport;
+pub mod websocket;
+
+use frame::{EncodedFrame, FrameBuffer};
+use stats::NetworkStats;
+
+/// Where the server listens unless configured otherwise, for both streams and datagrams.
+/// Every interface is listened on so players on the local network can join.
+pub const DEFAULT_SERVER_ADDRESS: &str = "0.0.0.0:8888";
+/// Where the server accepts websocket connections unless configured otherwise.
+pub const DEFAULT_WEBSOCKET_ADDRESS: &str = "127.0.0.1:8889";
+/// Frames bigger than this are rejected unless the protocol is configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 8 * 1024 * 1024;
+/// Packets of at least this many bytes are compressed once the handshake enabled it.
+pub const DEFAULT_COMPRESSION_THRESHOLD: u32 = 256;
 
 #[derive(Serialize, Deserialize)]
 pub struct RawPacket {
@@ -13,77 +38,271 @@ pub struct RawPacket {
 }
 
 pub struct AnyPacket {
-    pub type_id: TypeId,
+    pub id: PacketId,
     pub data: Box<[u8]>,
 }
 
 impl AnyPacket {
-    pub fn is<T: 'static>(&self) -> bool {
-        TypeId::of::<T>() == self.type_id
+    pub fn is<T: Packet>(&self) -> bool {
+        T::ID == self.id
+    }
+
+    pub fn decode<T: Packet>(&self) -> Result<T, DecodeError> {
+        if !self.is::<T>() {
+            return Err(DecodeError::UnknownId(self.id));
+        }
+
+        bincode::deserialize(&self.data).map_err(DecodeError::Malformed)
     }
+}
+
+pub type PacketId = u16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PacketDirection {
+    /// Sent by the server
+    Clientbound,
+    /// Sent by the client
+    Serverbound,
+    /// Sent by both sides
+    Common,
+}
+
+/// How a packet travels once the datagram channel is open, see [`datagram`].
+/// Until then, and when a packet does not fit in a datagram, every packet uses the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Delivery {
+    /// Over the stream, never lost nor reordered
+    ReliableOrdered,
+    /// Over datagrams, may be lost, dropped when older than the last one received
+    UnreliableSequenced,
+    /// Over datagrams, may be lost or reordered
+    Unreliable,
+}
 
-    pub fn try_decode<T: DeserializeOwned + 'static>(&self) -> Option<T> {
-        self.is::<T>()
-            .then(|| bincode::deserialize(&self.data).expect("Failed to deserialize data"))
+impl Delivery {
+    pub fn is_reliable(self) -> bool {
+        self == Delivery::ReliableOrdered
     }
+}
+
+/// Implemented with `#[derive(Packet)]`, see the `macros` crate.
+pub trait Packet: Serialize + DeserializeOwned + 'static {
+    const ID: PacketId;
+    const NAME: &'static str;
+    const DIRECTION: PacketDirection;
+    /// Frames after this packet are read differently, like once encryption starts,
+    /// so decoding pauses after it until it has been handled.
+    const CHANGES_FRAMING: bool = false;
+    const DELIVERY: Delivery = Delivery::ReliableOrdered;
+}
+
+/// Packets the server is allowed to send.
+pub trait ClientboundPacket: Packet {}
+/// Packets the client is allowed to send.
+pub trait ServerboundPacket: Packet {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PacketInfo {
+    pub name: &'static str,
+    pub direction: PacketDirection,
+    pub changes_framing: bool,
+    pub delivery: Delivery,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    UnknownId(PacketId),
+    Oversize {
+        size: u32,
+        max: u32,
+    },
+    Truncated,
+    Decompression(String),
+    Decryption,
+    /// A datagram carrying a token of another session
+    UnknownSession,
+    /// A datagram received before, or too old to tell
+    Replayed,
+    /// A reliable packet received over datagrams
+    WrongChannel(PacketId),
+    UnknownComponent(replication::ComponentId),
+    Malformed(bincode::Error),
+}
 
-    pub fn type_id(&self) -> TypeId {
-        self.type_id
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownId(id) => write!(f, "unknown packet id: {id}"),
+            DecodeError::Oversize { size, max } => {
+                write!(
+                    f,
+                    "frame of {size} bytes exceeds the maximum of {max} bytes"
+                )
+            }
+            DecodeError::Truncated => write!(f, "frame ended before the packet was complete"),
+            DecodeError::Decompression(e) => write!(f, "failed to decompress frame: {e}"),
+            DecodeError::Decryption => write!(f, "failed to decrypt or authenticate frame"),
+            DecodeError::UnknownSession => write!(f, "datagram belongs to another session"),
+            DecodeError::Replayed => write!(f, "datagram was already received"),
+            DecodeError::WrongChannel(id) => {
+                write!(f, "reliable packet {id} was received over datagrams")
+            }
+            DecodeError::UnknownComponent(id) => write!(f, "unknown replicated component: {id}"),
+            DecodeError::Malformed(e) => write!(f, "malformed packet: {e}"),
+        }
     }
 }
 
-type PacketId = u16;
+impl std::error::Error for DecodeError {}
 
 pub struct Protocol {
-    packets: BiMap<TypeId, PacketId>,
+    packets: BTreeMap<PacketId, PacketInfo>,
+    /// Mixed into the fingerprint, for tables packets depend on
+    extra_fingerprint: u64,
+    max_frame_size: u32,
 }
 
 impl Protocol {
     fn new() -> Self {
         Self {
-            packets: BiMap::new(),
+            packets: BTreeMap::new(),
+            extra_fingerprint: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
         }
     }
 
-    fn add_packet<T: 'static>(&mut self) -> &mut Self {
-        let tid = TypeId::of::<T>();
-        if self.packets.contains_left(&tid) {
-            panic!("Packet already exists");
+    fn add_packet<T: Packet>(&mut self) -> &mut Self {
+        if let Some(existing) = self.packets.get(&T::ID) {
+            panic!(
+                "Packet id {} is declared by both {} and {}",
+                T::ID,
+                existing.name,
+                T::NAME
+            );
         }
 
-        self.packets.insert(tid, self.packets.len() as u16);
+        self.packets.insert(
+            T::ID,
+            PacketInfo {
+                name: T::NAME,
+                direction: T::DIRECTION,
+                changes_framing: T::CHANGES_FRAMING,
+                delivery: T::DELIVERY,
+            },
+        );
         self
     }
 
-    pub fn id_of<T: 'static>(&self) -> Option<u16> {
-        self.packets.get_by_left(&TypeId::of::<T>()).copied()
+    /// Stable hash of the registered packet ids, names and directions.
+    /// Two builds with the same fingerprint agree on every packet.
+    pub fn fingerprint(&self) -> u64 {
+        // FNV-1a, std hashers are not guaranteed to be stable across builds
+        let mut hash: u64 = 0xcbf29ce484222325 ^ self.extra_fingerprint;
+        for (id, info) in &self.packets {
+            let bytes = id
+                .to_le_bytes()
+                .into_iter()
+                .chain(info.name.bytes())
+                .chain([info.direction as u8, info.delivery as u8, 0]);
+            for byte in bytes {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
     }
 
-    pub fn type_id_of(&self, id: PacketId) -> TypeId {
-        self.packets
-            .get_by_right(&id)
-            .unwrap_or_else(|| panic!("Invalid packet id: {}", id))
-            .clone()
+    fn include_in_fingerprint(&mut self, fingerprint: u64) {
+        self.extra_fingerprint ^= fingerprint;
     }
 
-    pub fn encode<T: Serialize + 'static>(&self, data: &T) -> Vec<u8> {
+    pub fn with_max_frame_size(mut self, max_frame_size: u32) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    pub fn info(&self, id: PacketId) -> Option<&PacketInfo> {
+        self.packets.get(&id)
+    }
+
+    /// Encodes a packet as a frame: a little-endian u32 length followed by the packet bytes,
+    /// compressed when they reach the threshold.
+    pub fn encode<T: Packet>(&self, data: &T, compression_threshold: Option<u32>) -> EncodedFrame {
+        frame::write_frame(&self.encode_packet(data), compression_threshold)
+    }
+
+    /// Encodes a packet without framing, as carried by datagrams.
+    pub fn encode_packet<T: Packet>(&self, data: &T) -> Vec<u8> {
+        if self.info(T::ID).map(|info| info.name) != Some(T::NAME) {
+            panic!(
+                "Tried to encode unknown packet: {}, include it in the protocol",
+                T::NAME
+            );
+        }
+
         let data_bin = bincode::serialize(data).expect("Failed to serialize data");
-        bincode::serialize(&RawPacket {
-            id: self.id_of::<T>().unwrap_or_else(|| {
-                panic!(
-                    "Tried to encode unknown packet: {}, include it in the protocol",
-                    std::any::type_name::<T>()
-                )
-            }),
+        let packet = bincode::serialize(&RawPacket {
+            id: T::ID,
             data: data_bin.into(),
         })
-        .expect("Failed to encode packet")
+        .expect("Failed to encode packet");
+
+        if packet.len() > self.max_frame_size as usize {
+            panic!(
+                "Tried to encode packet {} of {} bytes, which exceeds the maximum frame size",
+                T::NAME,
+                packet.len()
+            );
+        }
+
+        packet
     }
 
-    pub fn decode(&self, stream: impl Read) -> Result<AnyPacket, ErrorKind> {
-        let raw: RawPacket = bincode::deserialize_from(stream).map_err(|e| *e)?;
+    /// Decodes the next complete packet in the buffer, if any.
+    pub fn decode(
+        &self,
+        buffer: &mut FrameBuffer,
+        stats: &mut NetworkStats,
+    ) -> Result<Option<AnyPacket>, DecodeError> {
+        buffer
+            .next_frame(self.max_frame_size)?
+            .map(|body| self.decode_frame(body, stats))
+            .transpose()
+    }
+
+    /// Decodes a frame body, once decrypted if the session is encrypted.
+    pub fn decode_frame(
+        &self,
+        body: Vec<u8>,
+        stats: &mut NetworkStats,
+    ) -> Result<AnyPacket, DecodeError> {
+        let wire_size = body.len() + std::mem::size_of::<u32>();
+        let packet = frame::read_frame(body, self.max_frame_size)?;
+        stats.record_received(wire_size, packet.len());
+
+        self.decode_packet(&packet)
+    }
+
+    /// Decodes a packet without framing, as carried by datagrams.
+    pub fn decode_packet(&self, packet: &[u8]) -> Result<AnyPacket, DecodeError> {
+        let raw: RawPacket = bincode::deserialize(packet).map_err(|e| match *e {
+            bincode::ErrorKind::Io(ref io) if io.kind() == std::io::ErrorKind::UnexpectedEof => {
+                DecodeError::Truncated
+            }
+            _ => DecodeError::Malformed(e),
+        })?;
+
+        if self.info(raw.id).is_none() {
+            return Err(DecodeError::UnknownId(raw.id));
+        }
+
         Ok(AnyPacket {
-            type_id: self.type_id_of(raw.id),
+            id: raw.id,
             data: raw.data,
         })
     }
@@ -1,6 +1,8 @@
-use super::Protocol;
-use crate::core::{spatial::Position, EntityKind};
-use ecs::serde::EcsState;
+use super::{
+    replication::{self, EntitySnapshot, NetworkEntity},
+    Packet, Protocol,
+};
+use crate::core::{spatial::Position, tick::Tick};
 use serde::{Deserialize, Serialize};
 
 ///////////////////////////////
@@ -10,21 +12,106 @@ use serde::{Deserialize, Serialize};
 // Serverbound = From Client //
 ///////////////////////////////
 
-pub mod login {
+/// Bump whenever packets change in a way the fingerprint cannot detect, like a field change.
+/// Packet ids are declared with `#[packet(id = ..)]` and must stay unique.
+pub const PROTOCOL_VERSION: u32 = 12;
 
-    use crate::tilemap::TileMap;
+/// Handshake packet ids must never change, older builds rely on them to report a mismatch.
+pub mod handshake {
+    use crate::network::crypto::PublicKeyBytes;
 
     use super::*;
 
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x00, serverbound)]
+    pub struct ServerboundHandshake {
+        pub protocol_version: u32,
+        pub fingerprint: u64,
+    }
+
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x01, clientbound)]
+    pub struct ClientboundHandshakeRejected {
+        pub reason: String,
+    }
+
+    /// Every packet sent after this one is compressed once it reaches the threshold.
+    /// With an encryption offer, the client must answer with [`ServerboundKeyExchange`].
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x02, clientbound)]
+    pub struct ClientboundHandshakeSuccess {
+        pub compression_threshold: Option<u32>,
+        pub encryption: Option<EncryptionOffer>,
+    }
+
     #[derive(Serialize, Deserialize)]
+    pub struct EncryptionOffer {
+        /// Persistent server key, pinned by clients on first use
+        pub identity_key: PublicKeyBytes,
+        pub ephemeral_key: PublicKeyBytes,
+        /// Signature of the handshake transcript and ephemeral key by the identity key
+        pub signature: Vec<u8>,
+    }
+
+    /// Every frame after this one is encrypted, in both directions.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x03, serverbound, changes_framing)]
+    pub struct ServerboundKeyExchange {
+        pub ephemeral_key: PublicKeyBytes,
+    }
+
+    /// Sent instead of the handshake to query the server status, the server answers
+    /// with [`ClientboundStatusResponse`] then closes the connection.
+    /// Works across protocol versions, so server lists can show incompatible servers.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x04, serverbound)]
+    pub struct ServerboundStatusRequest;
+
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x05, clientbound)]
+    pub struct ClientboundStatusResponse {
+        pub status: ServerStatus,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ServerStatus {
+        pub name: String,
+        pub motd: String,
+        pub protocol_version: u32,
+        pub online_players: u32,
+        pub max_players: u32,
+        pub players: Vec<String>,
+    }
+
+    pub fn handshake_protocol(proto: &mut Protocol) {
+        proto
+            .add_packet::<ServerboundHandshake>()
+            .add_packet::<ClientboundHandshakeRejected>()
+            .add_packet::<ClientboundHandshakeSuccess>()
+            .add_packet::<ServerboundKeyExchange>()
+            .add_packet::<ServerboundStatusRequest>()
+            .add_packet::<ClientboundStatusResponse>();
+    }
+}
+
+pub mod login {
+
+    use crate::network::datagram::SessionToken;
+
+    use super::*;
+
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x10, serverbound)]
     pub struct ServerboundLoginStart {
         pub username: String,
     }
 
-    #[derive(Serialize, Deserialize)]
+    /// The datagram channel is reached at the server address, with the token
+    /// binding its datagrams to this session. Terrain is streamed once playing.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x11, clientbound)]
     pub struct ClientboundLoginSuccess {
-        pub ecs_state: EcsState<SyncComponentSelection>,
-        pub terrain: TileMap,
+        pub datagram_token: SessionToken,
     }
 
     pub fn login_protocol(proto: &mut Protocol) {
@@ -35,69 +122,179 @@ pub mod login {
 }
 
 pub mod play {
-    use ecs::{serde::EntityState, AliveEntityId};
+    use cgmath::Vector2;
+
+    use crate::{
+        core::movement::InputCommand,
+        tilemap::{ChunkCoord, TileChunk},
+        utils::registry::RecordId,
+    };
 
     use super::*;
 
-    #[derive(Serialize, Deserialize)]
-    pub struct ServerboundSetPlayerPos {
-        pub pos: Position,
+    /// Every input not yet acknowledged, oldest first, so a lost packet loses no input.
+    /// Holds at most `MAX_PENDING_INPUTS` of them.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x20, serverbound, unreliable_sequenced)]
+    pub struct ServerboundPlayerInputs {
+        pub inputs: Vec<InputCommand>,
     }
 
-    #[derive(Serialize, Deserialize)]
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x21, clientbound)]
     pub struct ClientboundSpawnEntity {
-        pub entity: AliveEntityId,
-        pub state: EntityState<SyncComponentSelection>,
+        pub snapshot: EntitySnapshot,
     }
 
-    #[derive(Serialize, Deserialize)]
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x22, clientbound)]
     pub struct ClientboundRemoveEntity {
-        pub entity: AliveEntityId,
+        pub entity: NetworkEntity,
     }
 
-    #[derive(Serialize, Deserialize)]
-    pub struct ClientboundSetEntityPosition {
-        pub entity: AliveEntityId,
+    /// Components that changed on the server since the last delta, batched for every entity.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x23, clientbound)]
+    pub struct ClientboundEntityDelta {
+        /// Tick the components changed on, clients render entities slightly behind it
+        pub tick: Tick,
+        pub entities: Vec<EntitySnapshot>,
+    }
+
+    /// Authoritative position of the player, once its inputs up to `last_input` are simulated.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x24, clientbound, unreliable_sequenced)]
+    pub struct ClientboundPlayerState {
+        pub tick: Tick,
         pub pos: Position,
+        pub last_input: u32,
+    }
+
+    /// Sent nearest first as the player moves, a chunk sent again replaces the previous one.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x25, clientbound)]
+    pub struct ClientboundChunkData {
+        pub coord: ChunkCoord,
+        pub chunk: TileChunk,
+    }
+
+    /// The player moved away from the chunk, or the server unloaded it.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x26, clientbound)]
+    pub struct ClientboundUnloadChunk {
+        pub coord: ChunkCoord,
+    }
+
+    /// Asks to place a tile, the server answers with a [`ClientboundTileUpdate`] if it agrees.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x27, serverbound)]
+    pub struct ServerboundSetTile {
+        pub pos: Vector2<i32>,
+        pub tile: RecordId,
+    }
+
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x28, serverbound)]
+    pub struct ServerboundBreakTile {
+        pub pos: Vector2<i32>,
+    }
+
+    /// A tile changed in a chunk the player has loaded.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x29, clientbound)]
+    pub struct ClientboundTileUpdate {
+        pub pos: Vector2<i32>,
+        pub tile: RecordId,
     }
 
     pub fn play_protocol(proto: &mut Protocol) {
         proto
-            .add_packet::<ServerboundSetPlayerPos>()
+            .add_packet::<ServerboundPlayerInputs>()
             .add_packet::<ClientboundSpawnEntity>()
             .add_packet::<ClientboundRemoveEntity>()
-            .add_packet::<ClientboundSetEntityPosition>();
+            .add_packet::<ClientboundEntityDelta>()
+            .add_packet::<ClientboundPlayerState>()
+            .add_packet::<ClientboundChunkData>()
+            .add_packet::<ClientboundUnloadChunk>()
+            .add_packet::<ServerboundSetTile>()
+            .add_packet::<ServerboundBreakTile>()
+            .add_packet::<ClientboundTileUpdate>();
     }
 }
 
 pub mod extra {
-    use std::time::Instant;
+    use std::{fmt, time::Instant};
 
     use super::*;
 
-    #[derive(Serialize, Deserialize)]
+    /// Echoed back by the server with its current tick, which the client syncs its clock to.
+    /// The client sends the tick it estimates the server is at.
+    #[derive(Packet, Serialize, Deserialize)]
+    #[packet(id = 0x30, common)]
     pub struct CommonPing {
         #[serde(with = "serde_millis")]
         pub time: Instant,
+        pub tick: Tick,
     }
 
-    #[derive(Debug, Serialize, Deserialize)]
+    #[derive(Debug, Packet, Serialize, Deserialize)]
+    #[packet(id = 0x31, serverbound)]
     pub enum ServerboundDisconnect {
         GameClosed,
     }
 
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub enum DisconnectReason {
+        Kicked(String),
+        ServerShuttingDown,
+        TimedOut,
+        ProtocolMismatch(String),
+        DuplicateUsername,
+        ServerFull,
+        /// Never sent, used by the client when the connection drops without a reason
+        ConnectionLost(String),
+        /// Never sent, used by the client when the server identity cannot be trusted
+        UntrustedServer(String),
+    }
+
+    impl fmt::Display for DisconnectReason {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                DisconnectReason::Kicked(reason) => write!(f, "Kicked: {reason}"),
+                DisconnectReason::ServerShuttingDown => write!(f, "Server is shutting down"),
+                DisconnectReason::TimedOut => write!(f, "Timed out"),
+                DisconnectReason::ProtocolMismatch(reason) => write!(f, "{reason}"),
+                DisconnectReason::DuplicateUsername => {
+                    write!(f, "A player with this username is already connected")
+                }
+                DisconnectReason::ServerFull => write!(f, "The server is full"),
+                DisconnectReason::ConnectionLost(reason) => write!(f, "Connection lost: {reason}"),
+                DisconnectReason::UntrustedServer(reason) => {
+                    write!(f, "Untrusted server: {reason}")
+                }
+            }
+        }
+    }
+
+    #[derive(Debug, Packet, Serialize, Deserialize)]
+    #[packet(id = 0x32, clientbound)]
+    pub struct ClientboundDisconnect {
+        pub reason: DisconnectReason,
+    }
+
     pub fn extra_protocol(proto: &mut Protocol) {
         proto
             .add_packet::<CommonPing>()
-            .add_packet::<ServerboundDisconnect>();
+            .add_packet::<ServerboundDisconnect>()
+            .add_packet::<ClientboundDisconnect>();
     }
 }
 
-pub type SyncComponentSelection = (EntityKind, Position);
-
 pub fn network_protocol() -> Protocol {
     let mut proto = Protocol::new();
+    proto.include_in_fingerprint(replication::replicated_components().fingerprint());
 
+    handshake::handshake_protocol(&mut proto);
     login::login_protocol(&mut proto);
     play::play_protocol(&mut proto);
     extra::extra_protocol(&mut proto);
@@ -0,0 +1,200 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+};
+
+use ecs::{Entity, EntityHandle};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::core::{spatial::Position, EntityKind};
+
+use super::DecodeError;
+
+/// Identifies a replicated entity on the wire, assigned by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NetworkEntity(pub u32);
+
+/// Index of a component in [`ReplicatedComponents`], the same on both sides.
+pub type ComponentId = u16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicationPolicy {
+    /// Sent with the entity, never updated
+    OnSpawn,
+    /// Sent whenever its value changes, and once more on the tick after it stops changing
+    /// so interpolating clients see it settle
+    OnChange,
+    /// Sent every n ticks, changed or not
+    EveryTicks(u32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentData {
+    pub component: ComponentId,
+    pub data: Box<[u8]>,
+}
+
+/// Components of one entity, all of them when spawned, only the changed ones in a delta.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntitySnapshot {
+    pub entity: NetworkEntity,
+    pub components: Vec<ComponentData>,
+}
+
+struct ComponentInfo {
+    name: &'static str,
+    policy: ReplicationPolicy,
+    encode: fn(&EntityHandle) -> Option<Box<[u8]>>,
+    decode: fn(&[u8]) -> bincode::Result<Box<dyn Any>>,
+    insert: fn(&mut EntityHandle, Box<dyn Any>),
+}
+
+fn encode<T: Serialize + 'static>(entity: &EntityHandle) -> Option<Box<[u8]>> {
+    let component = entity.get::<T>()?;
+    let data = bincode::serialize(&*component).expect("Failed to serialize component");
+    Some(data.into())
+}
+
+fn decode<T: DeserializeOwned + 'static>(data: &[u8]) -> bincode::Result<Box<dyn Any>> {
+    Ok(Box::new(bincode::deserialize::<T>(data)?))
+}
+
+fn insert<T: 'static>(entity: &mut EntityHandle, value: Box<dyn Any>) {
+    let value = value
+        .downcast::<T>()
+        .expect("Component value of the wrong type");
+    entity.set(*value);
+}
+
+/// A decoded component, ready to be inserted into an entity.
+pub struct ComponentValue {
+    value: Box<dyn Any>,
+    insert: fn(&mut EntityHandle, Box<dyn Any>),
+}
+
+impl ComponentValue {
+    pub fn downcast<T: 'static>(self) -> Result<T, Self> {
+        match self.value.downcast::<T>() {
+            Ok(value) => Ok(*value),
+            Err(value) => Err(Self { value, ..self }),
+        }
+    }
+
+    pub fn insert_into(self, entity: &mut EntityHandle) {
+        (self.insert)(entity, self.value)
+    }
+}
+
+/// Components synced from the server to clients, registered in the same order on both sides.
+pub struct ReplicatedComponents {
+    components: Vec<ComponentInfo>,
+    ids: HashMap<TypeId, ComponentId>,
+}
+
+impl ReplicatedComponents {
+    fn new() -> Self {
+        Self {
+            components: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    fn register<T: Serialize + DeserializeOwned + 'static>(
+        &mut self,
+        name: &'static str,
+        policy: ReplicationPolicy,
+    ) -> &mut Self {
+        if self.ids.contains_key(&TypeId::of::<T>()) {
+            panic!("Component {name} is replicated twice");
+        }
+
+        let id = self.components.len() as ComponentId;
+        self.ids.insert(TypeId::of::<T>(), id);
+        self.components.push(ComponentInfo {
+            name,
+            policy,
+            encode: encode::<T>,
+            decode: decode::<T>,
+            insert: insert::<T>,
+        });
+        self
+    }
+
+    pub fn id<T: 'static>(&self) -> Option<ComponentId> {
+        self.ids.get(&TypeId::of::<T>()).copied()
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = ComponentId> {
+        0..self.components.len() as ComponentId
+    }
+
+    pub fn policy(&self, id: ComponentId) -> ReplicationPolicy {
+        self.components[id as usize].policy
+    }
+
+    pub fn name(&self, id: ComponentId) -> &'static str {
+        self.components[id as usize].name
+    }
+
+    /// Stable hash of the registered names and policies, part of the protocol fingerprint.
+    pub fn fingerprint(&self) -> u64 {
+        // FNV-1a, like the packet table fingerprint
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for info in &self.components {
+            let policy = match info.policy {
+                ReplicationPolicy::OnSpawn => [0; 5],
+                ReplicationPolicy::OnChange => [1, 0, 0, 0, 0],
+                ReplicationPolicy::EveryTicks(n) => {
+                    let [a, b, c, d] = n.to_le_bytes();
+                    [2, a, b, c, d]
+                }
+            };
+            for byte in info.name.bytes().chain(policy).chain([0]) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    /// The value of a component, if the entity has it.
+    pub fn encode(&self, id: ComponentId, entity: &EntityHandle) -> Option<ComponentData> {
+        let data = (self.components[id as usize].encode)(entity)?;
+        Some(ComponentData {
+            component: id,
+            data,
+        })
+    }
+
+    /// Every replicated component the entity has.
+    pub fn snapshot(&self, entity: NetworkEntity, handle: &EntityHandle) -> EntitySnapshot {
+        EntitySnapshot {
+            entity,
+            components: self
+                .ids()
+                .filter_map(|id| self.encode(id, handle))
+                .collect(),
+        }
+    }
+
+    pub fn decode(&self, component: &ComponentData) -> Result<ComponentValue, DecodeError> {
+        let info = self
+            .components
+            .get(component.component as usize)
+            .ok_or(DecodeError::UnknownComponent(component.component))?;
+
+        Ok(ComponentValue {
+            value: (info.decode)(&component.data).map_err(DecodeError::Malformed)?,
+            insert: info.insert,
+        })
+    }
+}
+
+/// Register new synced components here, no packet needs to change.
+pub fn replicated_components() -> ReplicatedComponents {
+    let mut components = ReplicatedComponents::new();
+    components
+        .register::<EntityKind>("kind", ReplicationPolicy::OnSpawn)
+        .register::<Position>("position", ReplicationPolicy::OnChange);
+    components
+}
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use super::{AnyPacket, DecodeError, Packet, PacketId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    Handshake,
+    Login,
+    Play,
+    /// Answering a status query, the connection is closed once the answer is sent
+    Status,
+    /// Disconnected, only waiting for queued packets to be flushed
+    Closing,
+}
+
+type Handler<Ctx, Src> = Rc<dyn Fn(&mut Ctx, Src, &AnyPacket) -> Result<(), DecodeError>>;
+
+/// Dispatches incoming packets to the handler registered for the packet and connection state.
+/// `Src` identifies the sender, like the client address on the server.
+pub struct PacketRouter<Ctx, Src = ()> {
+    handlers: HashMap<(ConnectionState, PacketId), Handler<Ctx, Src>>,
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    Unhandled {
+        state: ConnectionState,
+        id: PacketId,
+    },
+    Decode(DecodeError),
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::Unhandled { state, id } => {
+                write!(f, "no handler for packet {id:#04x} in the {state:?} state")
+            }
+            RouteError::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+impl<Ctx: 'static, Src: 'static> Default for PacketRouter<Ctx, Src> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Ctx: 'static, Src: 'static> PacketRouter<Ctx, Src> {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn on<T: Packet>(
+        &mut self,
+        state: ConnectionState,
+        handler: impl Fn(&mut Ctx, Src, T) + 'static,
+    ) -> &mut Self {
+        self.on_each(&[state], handler)
+    }
+
+    /// Registers the same handler for several connection states.
+    pub fn on_each<T: Packet>(
+        &mut self,
+        states: &[ConnectionState],
+        handler: impl Fn(&mut Ctx, Src, T) + 'static,
+    ) -> &mut Self {
+        let handler: Handler<Ctx, Src> = Rc::new(move |ctx, src, packet| {
+            handler(ctx, src, packet.decode()?);
+            Ok(())
+        });
+
+        for &state in states {
+            if self
+                .handlers
+                .insert((state, T::ID), handler.clone())
+                .is_some()
+            {
+                panic!(
+                    "Packet {} already has a handler in the {state:?} state",
+                    T::NAME
+                );
+            }
+        }
+        self
+    }
+
+    pub fn handles(&self, state: ConnectionState, id: PacketId) -> bool {
+        self.handlers.contains_key(&(state, id))
+    }
+
+    pub fn route(
+        &self,
+        ctx: &mut Ctx,
+        state: ConnectionState,
+        src: Src,
+        packet: AnyPacket,
+    ) -> Result<(), RouteError> {
+        let handler = self
+            .handlers
+            .get(&(state, packet.id))
+            .ok_or(RouteError::Unhandled {
+                state,
+                id: packet.id,
+            })?;
+
+        handler(ctx, src, &packet).map_err(RouteError::Decode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::proto::handshake::{ServerboundHandshake, ServerboundStatusRequest};
+
+    use super::*;
+
+    fn any_packet<T: Packet>(packet: &T) -> AnyPacket {
+        AnyPacket {
+            id: T::ID,
+            data: bincode::serialize(packet).unwrap().into(),
+        }
+    }
+
+    fn router() -> PacketRouter<Vec<&'static str>> {
+        let mut router = PacketRouter::new();
+        router
+            .on(
+                ConnectionState::Handshake,
+                |handled: &mut Vec<_>, (), _: ServerboundHandshake| handled.push("handshake"),
+            )
+            .on(
+                ConnectionState::Handshake,
+                |handled: &mut Vec<_>, (), _: ServerboundStatusRequest| handled.push("status"),
+            );
+        router
+    }
+
+    #[test]
+    fn routes_by_state_and_packet() {
+        let router = router();
+        let mut handled = Vec::new();
+        let handshake = ServerboundHandshake {
+            protocol_version: 1,
+            fingerprint: 2,
+        };
+
+        router
+            .route(
+                &mut handled,
+                ConnectionState::Handshake,
+                (),
+                any_packet(&ServerboundStatusRequest),
+            )
+            .unwrap();
+        router
+            .route(
+                &mut handled,
+                ConnectionState::Handshake,
+                (),
+                any_packet(&handshake),
+            )
+            .unwrap();
+        assert_eq!(handled, ["status", "handshake"]);
+    }
+
+    #[test]
+    fn rejects_packet_in_wrong_state() {
+        let router = router();
+        let mut handled = Vec::new();
+        let handshake = ServerboundHandshake {
+            protocol_version: 1,
+            fingerprint: 2,
+        };
+
+        let result = router.route(
+            &mut handled,
+            ConnectionState::Play,
+            (),
+            any_packet(&handshake),
+        );
+        assert!(matches!(
+            result,
+            Err(RouteError::Unhandled { state: ConnectionState::Play, id })
+                if id == ServerboundHandshake::ID
+        ));
+        assert!(handled.is_empty());
+        assert!(!router.handles(ConnectionState::Login, ServerboundHandshake::ID));
+    }
+}
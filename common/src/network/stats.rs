@@ -0,0 +1,51 @@
+use std::fmt;
+
+/// Traffic counters, wire sizes are after compression.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct NetworkStats {
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub uncompressed_bytes_sent: u64,
+    pub uncompressed_bytes_received: u64,
+}
+
+impl NetworkStats {
+    pub fn record_sent(&mut self, wire_size: usize, uncompressed_size: usize) {
+        self.packets_sent += 1;
+        self.bytes_sent += wire_size as u64;
+        self.uncompressed_bytes_sent += uncompressed_size as u64;
+    }
+
+    pub fn record_received(&mut self, wire_size: usize, uncompressed_size: usize) {
+        self.packets_received += 1;
+        self.bytes_received += wire_size as u64;
+        self.uncompressed_bytes_received += uncompressed_size as u64;
+    }
+
+    pub fn merge(&mut self, other: &NetworkStats) {
+        self.packets_sent += other.packets_sent;
+        self.packets_received += other.packets_received;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.uncompressed_bytes_sent += other.uncompressed_bytes_sent;
+        self.uncompressed_bytes_received += other.uncompressed_bytes_received;
+    }
+}
+
+impl fmt::Display for NetworkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kib = |bytes: u64| bytes as f32 / 1024.;
+        write!(
+            f,
+            "Sent: {} packets, {:.1} KiB ({:.1} KiB uncompressed)\nReceived: {} packets, {:.1} KiB ({:.1} KiB uncompressed)",
+            self.packets_sent,
+            kib(self.bytes_sent),
+            kib(self.uncompressed_bytes_sent),
+            self.packets_received,
+            kib(self.bytes_received),
+            kib(self.uncompressed_bytes_received),
+        )
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// A reliable, ordered byte stream to a peer.
+/// Reads and writes never block, they fail with `WouldBlock` instead.
+pub trait Transport: Read + Write {
+    /// Where the peer receives datagrams, `None` when the transport has no datagram channel.
+    fn datagram_addr(&self) -> Option<SocketAddr>;
+}
+
+/// Hands out the transports of new peers.
+pub trait TransportListener {
+    /// Accepts a pending connection, if any, with the address identifying the peer.
+    fn accept(&mut self) -> io::Result<Option<(Box<dyn Transport>, SocketAddr)>>;
+
+    /// Whether no connection can be accepted anymore.
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+impl Transport for TcpStream {
+    fn datagram_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+}
+
+impl TransportListener for TcpListener {
+    fn accept(&mut self) -> io::Result<Option<(Box<dyn Transport>, SocketAddr)>> {
+        match TcpListener::accept(self) {
+            Ok((socket, addr)) => {
+                socket.set_nonblocking(true)?;
+                Ok(Some((Box::new(socket), addr)))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub fn connect_tcp(address: impl ToSocketAddrs) -> io::Result<TcpStream> {
+    let socket = TcpStream::connect(address)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Gives up on each address the peer resolves to after `timeout`.
+pub fn connect_tcp_timeout(
+    address: impl ToSocketAddrs,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let mut result = Err(io::Error::new(
+        ErrorKind::InvalidInput,
+        "The address did not resolve",
+    ));
+    for addr in address.to_socket_addrs()? {
+        result = TcpStream::connect_timeout(&addr, timeout);
+        if result.is_ok() {
+            break;
+        }
+    }
+
+    let socket = result?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub fn listen_tcp(address: impl ToSocketAddrs) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
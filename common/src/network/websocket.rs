@@ -0,0 +1,239 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use tungstenite::{
+    handshake::{
+        server::{NoCallback, ServerHandshake},
+        HandshakeError, MidHandshake,
+    },
+    protocol::WebSocketConfig,
+    Message, WebSocket,
+};
+
+use crate::logger::warn;
+
+use super::{
+    transport::{self, Transport, TransportListener},
+    DEFAULT_MAX_FRAME_SIZE,
+};
+
+const FRAME_HEADER_SIZE: usize = std::mem::size_of::<u32>();
+/// Messages the websocket may buffer before writes report `WouldBlock`,
+/// the rest of the backlog stays in the connection.
+const MAX_WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+/// How long a peer may take to complete the websocket handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+type PendingHandshake = MidHandshake<ServerHandshake<TcpStream, NoCallback>>;
+
+fn config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_write_buffer_size: MAX_WRITE_BUFFER_SIZE,
+        max_message_size: Some(FRAME_HEADER_SIZE + DEFAULT_MAX_FRAME_SIZE as usize),
+        max_frame_size: Some(FRAME_HEADER_SIZE + DEFAULT_MAX_FRAME_SIZE as usize),
+        ..Default::default()
+    }
+}
+
+fn io_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => {
+            ErrorKind::ConnectionAborted.into()
+        }
+        e => io::Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+/// Frames carried as binary websocket messages, one frame with its length prefix per message,
+/// so browsers can speak the protocol.
+pub struct WebSocketTransport {
+    socket: WebSocket<TcpStream>,
+    incoming: VecDeque<u8>,
+    /// Start of the frame being written
+    outgoing: Vec<u8>,
+    /// Frame rejected because the websocket write buffer was full
+    blocked: Option<Message>,
+}
+
+impl WebSocketTransport {
+    fn new(socket: WebSocket<TcpStream>) -> Self {
+        Self {
+            socket,
+            incoming: VecDeque::new(),
+            outgoing: Vec::new(),
+            blocked: None,
+        }
+    }
+
+    /// Bytes still missing from the frame being written, or from its header.
+    fn frame_remaining(&self) -> usize {
+        match self.outgoing.get(..FRAME_HEADER_SIZE) {
+            Some(header) => {
+                let size = u32::from_le_bytes(header.try_into().unwrap()) as usize;
+                FRAME_HEADER_SIZE + size - self.outgoing.len()
+            }
+            None => FRAME_HEADER_SIZE - self.outgoing.len(),
+        }
+    }
+
+    fn write_message(&mut self, message: Message) -> io::Result<()> {
+        match self.socket.write(message) {
+            Ok(()) => Ok(()),
+            // The message is buffered and sent by a later flush
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(tungstenite::Error::WriteBufferFull(message)) => {
+                self.blocked = Some(message);
+                Err(ErrorKind::WouldBlock.into())
+            }
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    fn flush_socket(&mut self) -> io::Result<()> {
+        match self.socket.flush() {
+            Ok(()) => Ok(()),
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
+
+impl Read for WebSocketTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.incoming.is_empty() {
+            match self.socket.read() {
+                Ok(Message::Binary(data)) => self.incoming.extend(data),
+                Ok(Message::Text(_)) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "text messages are not part of the protocol",
+                    ))
+                }
+                Ok(Message::Close(_)) => return Ok(0),
+                Ok(_) => {} // Pings are answered by the next flush
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                    return Ok(0)
+                }
+                Err(e) => return Err(io_error(e)),
+            }
+        }
+
+        let read = self.incoming.len().min(buf.len());
+        for (byte, incoming) in buf.iter_mut().zip(self.incoming.drain(..read)) {
+            *byte = incoming;
+        }
+        Ok(read)
+    }
+}
+
+impl Write for WebSocketTransport {
+    /// Accepts at most the rest of the current frame, sending it once complete.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(message) = self.blocked.take() {
+            self.write_message(message)?;
+        }
+
+        let taken = self.frame_remaining().min(buf.len());
+        self.outgoing.extend_from_slice(&buf[..taken]);
+
+        if self.outgoing.len() >= FRAME_HEADER_SIZE && self.frame_remaining() == 0 {
+            let frame = std::mem::take(&mut self.outgoing);
+            match self.write_message(Message::Binary(frame)) {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+                _ => {} // A blocked frame is retried by the next write or flush
+            }
+        }
+
+        Ok(taken)
+    }
+
+    /// Also retries a blocked frame, the last one sent before the connection goes idle
+    /// would otherwise never be.
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(message) = self.blocked.take() {
+            self.flush_socket()?; // Makes room in the write buffer
+            match self.write_message(message) {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+                _ => {}
+            }
+        }
+
+        self.flush_socket()
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn datagram_addr(&self) -> Option<SocketAddr> {
+        None // Browsers cannot send udp datagrams
+    }
+}
+
+/// Accepts websocket connections, completing their handshakes across calls.
+pub struct WebSocketListener {
+    listener: TcpListener,
+    handshakes: Vec<(PendingHandshake, SocketAddr, Instant)>,
+    ready: VecDeque<(WebSocket<TcpStream>, SocketAddr)>,
+}
+
+impl WebSocketListener {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: transport::listen_tcp(address)?,
+            handshakes: Vec::new(),
+            ready: VecDeque::new(),
+        })
+    }
+
+    fn progress(
+        &mut self,
+        handshake: Result<
+            WebSocket<TcpStream>,
+            HandshakeError<ServerHandshake<TcpStream, NoCallback>>,
+        >,
+        addr: SocketAddr,
+        started: Instant,
+    ) {
+        match handshake {
+            Ok(socket) => self.ready.push_back((socket, addr)),
+            Err(HandshakeError::Interrupted(pending)) if started.elapsed() < HANDSHAKE_TIMEOUT => {
+                self.handshakes.push((pending, addr, started));
+            }
+            Err(HandshakeError::Interrupted(_)) => {
+                warn!("Websocket client {addr} did not complete the handshake in time");
+            }
+            Err(HandshakeError::Failure(e)) => {
+                warn!("Websocket handshake with {addr} failed: {e}");
+            }
+        }
+    }
+}
+
+impl TransportListener for WebSocketListener {
+    fn accept(&mut self) -> io::Result<Option<(Box<dyn Transport>, SocketAddr)>> {
+        for (pending, addr, started) in std::mem::take(&mut self.handshakes) {
+            self.progress(pending.handshake(), addr, started);
+        }
+
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => {
+                    stream.set_nonblocking(true)?;
+                    let handshake = tungstenite::accept_with_config(stream, Some(config()));
+                    self.progress(handshake, addr, Instant::now());
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(self.ready.pop_front().map(|(socket, addr)| {
+            let transport: Box<dyn Transport> = Box::new(WebSocketTransport::new(socket));
+            (transport, addr)
+        }))
+    }
+}
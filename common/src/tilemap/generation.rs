@@ -0,0 +1,218 @@
+use cgmath::Vector2;
+use rand_core::{OsRng, RngCore};
+
+use crate::utils::{
+    noise::{hash_unit, Fbm},
+    registry::{RecordId, Registry},
+};
+
+use super::{tile::Tile, ChunkCoord, TileChunk};
+
+pub fn random_seed() -> u64 {
+    OsRng.next_u64()
+}
+
+/// Climate of a tile, each value roughly in `[-1, 1]`.
+#[derive(Debug, Clone, Copy)]
+pub struct Climate {
+    pub elevation: f64,
+    pub moisture: f64,
+    pub temperature: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Biome {
+    Ocean,
+    Beach,
+    Plains,
+    Forest,
+    Desert,
+    Tundra,
+    Mountains,
+    SnowyPeaks,
+}
+
+impl Biome {
+    /// In declaration order.
+    pub const ALL: [Biome; 8] = [
+        Biome::Ocean,
+        Biome::Beach,
+        Biome::Plains,
+        Biome::Forest,
+        Biome::Desert,
+        Biome::Tundra,
+        Biome::Mountains,
+        Biome::SnowyPeaks,
+    ];
+
+    pub fn from_climate(climate: Climate) -> Biome {
+        let Climate {
+            elevation,
+            moisture,
+            temperature,
+        } = climate;
+
+        if elevation < -0.2 {
+            Biome::Ocean
+        } else if elevation < -0.12 {
+            Biome::Beach
+        } else if elevation > 0.45 {
+            if temperature < 0. {
+                Biome::SnowyPeaks
+            } else {
+                Biome::Mountains
+            }
+        } else if temperature < -0.3 {
+            Biome::Tundra
+        } else if temperature > 0.2 && moisture < -0.15 {
+            Biome::Desert
+        } else if moisture > 0.2 {
+            Biome::Forest
+        } else {
+            Biome::Plains
+        }
+    }
+
+    /// Labels of the main tile and of the one scattered over it, with its share of the tiles.
+    fn tile_labels(self) -> (&'static str, Option<(&'static str, f64)>) {
+        match self {
+            Biome::Ocean => ("water", None),
+            Biome::Beach => ("sand", None),
+            Biome::Plains => ("grass", Some(("dirt", 0.05))),
+            Biome::Forest => ("grass", Some(("dirt", 0.3))),
+            Biome::Desert => ("sand", Some(("stone", 0.03))),
+            Biome::Tundra => ("snow", Some(("stone", 0.08))),
+            Biome::Mountains => ("stone", Some(("dirt", 0.1))),
+            Biome::SnowyPeaks => ("snow", Some(("stone", 0.2))),
+        }
+    }
+}
+
+struct BiomeTiles {
+    main: RecordId,
+    scattered: Option<(RecordId, f64)>,
+}
+
+/// Generates terrain from layered noise, the same chunk for the same seed and coordinate.
+pub struct WorldGenerator {
+    seed: u64,
+    elevation: Fbm,
+    moisture: Fbm,
+    temperature: Fbm,
+    /// Indexed by biome
+    biomes: Vec<BiomeTiles>,
+}
+
+impl WorldGenerator {
+    /// Panics if a biome tile is missing from the registry.
+    pub fn new(seed: u64, tiles: &Registry<Tile>) -> Self {
+        let biomes = Biome::ALL
+            .iter()
+            .map(|biome| {
+                let (main, scattered) = biome.tile_labels();
+                BiomeTiles {
+                    main: tiles.get_id(main),
+                    scattered: scattered.map(|(label, share)| (tiles.get_id(label), share)),
+                }
+            })
+            .collect();
+
+        // Each layer gets its own seed so they do not correlate
+        let layer = |n: u64| seed ^ n.wrapping_mul(0x9e3779b97f4a7c15);
+        Self {
+            seed,
+            elevation: Fbm::new(layer(1), 1. / 96., 5),
+            moisture: Fbm::new(layer(2), 1. / 160., 3),
+            temperature: Fbm::new(layer(3), 1. / 256., 2),
+            biomes,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn climate(&self, tile: Vector2<i32>) -> Climate {
+        let (x, y) = (tile.x as f64, tile.y as f64);
+        Climate {
+            elevation: self.elevation.get(x, y),
+            moisture: self.moisture.get(x, y),
+            temperature: self.temperature.get(x, y),
+        }
+    }
+
+    pub fn biome(&self, tile: Vector2<i32>) -> Biome {
+        Biome::from_climate(self.climate(tile))
+    }
+
+    pub fn tile(&self, tile: Vector2<i32>) -> RecordId {
+        let tiles = &self.biomes[self.biome(tile) as usize];
+
+        match tiles.scattered {
+            Some((scattered, share))
+                if hash_unit(self.seed, tile.x as i64, tile.y as i64) < share =>
+            {
+                scattered
+            }
+            _ => tiles.main,
+        }
+    }
+
+    pub fn generate_chunk(&self, coord: ChunkCoord) -> TileChunk {
+        let origin = coord.to_tile_coords();
+        let mut chunk = TileChunk::new_filled(self.biomes[0].main);
+        for (y, row) in chunk.tiles.iter_mut().enumerate() {
+            for (x, tile) in row.iter_mut().enumerate() {
+                *tile = self.tile(origin + Vector2::new(x as i32, y as i32));
+            }
+        }
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiles() -> Registry<Tile> {
+        let mut tiles = Registry::new();
+        for label in ["water", "sand", "grass", "dirt", "stone", "snow"] {
+            tiles.register(label.to_string(), Tile::default());
+        }
+        tiles
+    }
+
+    fn coords() -> Vec<ChunkCoord> {
+        [(0, 0), (3, -2), (-1, -1), (-7, 12), (40, -40)]
+            .into_iter()
+            .map(|(x, y)| ChunkCoord(Vector2::new(x, y)))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_generates_same_chunks() {
+        let tiles = tiles();
+        let first = WorldGenerator::new(42, &tiles);
+        let second = WorldGenerator::new(42, &tiles);
+
+        for coord in coords() {
+            assert_eq!(
+                first.generate_chunk(coord).tiles,
+                second.generate_chunk(coord).tiles,
+                "chunk {coord:?} differs"
+            );
+        }
+    }
+
+    #[test]
+    fn other_seed_generates_other_chunks() {
+        let tiles = tiles();
+        let first = WorldGenerator::new(42, &tiles);
+        let second = WorldGenerator::new(43, &tiles);
+
+        // A single chunk could be all water for both seeds
+        assert!(coords()
+            .into_iter()
+            .any(|coord| first.generate_chunk(coord).tiles != second.generate_chunk(coord).tiles));
+    }
+}
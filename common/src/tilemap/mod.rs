@@ -1,39 +1,103 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use cgmath::Vector2;
 use serde::{Deserialize, Serialize};
 
 use crate::utils::registry::RecordId;
 
+pub mod generation;
 pub mod tile;
 
-#[derive(Clone, Serialize, Deserialize)]
+/// The loaded chunks of an unbounded world.
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct TileMap {
     pub chunks: HashMap<ChunkCoord, TileChunk>,
+    /// Loaded chunks changed since they were loaded
+    #[serde(skip)]
+    dirty: HashSet<ChunkCoord>,
 }
 
 impl TileMap {
-    pub fn generate(size: Vector2<i32>, background: RecordId) -> Self {
-        let chunk_size = size.zip(CHUNK_SIZE, |i, j| i / j);
-        let mut chunks = HashMap::with_capacity((chunk_size.x * chunk_size.y) as usize);
-        let (xstart, ystart) = (-chunk_size.x / 2, -chunk_size.y / 2);
-        let xrange = xstart..(xstart + chunk_size.x);
-        let yrange = ystart..(ystart + chunk_size.y);
-
-        for x in xrange {
-            for y in yrange.clone() {
-                chunks.insert(
-                    ChunkCoord(Vector2::new(x, y)),
-                    TileChunk::new_filled(background),
-                );
-            }
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_loaded(&self, coord: ChunkCoord) -> bool {
+        self.chunks.contains_key(&coord)
+    }
+
+    pub fn chunk(&self, coord: ChunkCoord) -> Option<&TileChunk> {
+        self.chunks.get(&coord)
+    }
+
+    /// Loads the chunk with `load` unless it already is, returning whether it was loaded now.
+    pub fn ensure_loaded(
+        &mut self,
+        coord: ChunkCoord,
+        load: impl FnOnce(ChunkCoord) -> TileChunk,
+    ) -> bool {
+        if self.chunks.contains_key(&coord) {
+            return false;
+        }
+        self.chunks.insert(coord, load(coord));
+        true
+    }
+
+    /// `None` if its chunk is not loaded.
+    pub fn tile(&self, pos: Vector2<i32>) -> Option<RecordId> {
+        let (coord, local) = ChunkCoord::split_tile(pos);
+        Some(self.chunks.get(&coord)?.tiles[local.y][local.x])
+    }
+
+    /// Changes a tile of a loaded chunk and marks it dirty, returning whether it was loaded.
+    pub fn set_tile(&mut self, pos: Vector2<i32>, tile: RecordId) -> bool {
+        let (coord, local) = ChunkCoord::split_tile(pos);
+        let Some(chunk) = self.chunks.get_mut(&coord) else {
+            return false;
+        };
+        chunk.tiles[local.y][local.x] = tile;
+        self.dirty.insert(coord);
+        true
+    }
+
+    /// Replaces the chunk with one received as is, like a chunk sent by the server.
+    pub fn insert(&mut self, coord: ChunkCoord, chunk: TileChunk) {
+        self.chunks.insert(coord, chunk);
+        self.dirty.remove(&coord);
+    }
+
+    /// Removes the chunk from memory, with whether it changed since it was loaded.
+    pub fn unload(&mut self, coord: ChunkCoord) -> Option<(TileChunk, bool)> {
+        let chunk = self.chunks.remove(&coord)?;
+        Some((chunk, self.dirty.remove(&coord)))
+    }
+
+    pub fn loaded_chunks(&self) -> impl Iterator<Item = (ChunkCoord, &TileChunk)> + '_ {
+        self.chunks.iter().map(|(coord, chunk)| (*coord, chunk))
+    }
+
+    /// Marks a chunk as changed, so it is saved before being unloaded.
+    pub fn mark_dirty(&mut self, coord: ChunkCoord) {
+        if self.chunks.contains_key(&coord) {
+            self.dirty.insert(coord);
         }
+    }
+
+    pub fn is_dirty(&self, coord: ChunkCoord) -> bool {
+        self.dirty.contains(&coord)
+    }
+
+    pub fn dirty_chunks(&self) -> impl Iterator<Item = ChunkCoord> + '_ {
+        self.dirty.iter().copied()
+    }
 
-        TileMap { chunks }
+    /// Forgets the changes once every dirty chunk is saved.
+    pub fn mark_clean(&mut self) {
+        self.dirty.clear();
     }
 }
 
-const CHUNK_SIZE: Vector2<i32> = Vector2::new(16, 16);
+pub const CHUNK_SIZE: Vector2<i32> = Vector2::new(16, 16);
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct TileChunk {
@@ -52,7 +116,29 @@ impl TileChunk {
 pub struct ChunkCoord(pub Vector2<i32>);
 
 impl ChunkCoord {
+    /// The chunk containing a tile.
+    pub fn from_tile(tile: Vector2<i32>) -> Self {
+        Self(tile.zip(CHUNK_SIZE, i32::div_euclid))
+    }
+
+    /// The chunk containing a tile, with the position of the tile in it.
+    pub fn split_tile(tile: Vector2<i32>) -> (Self, Vector2<usize>) {
+        let local = tile.zip(CHUNK_SIZE, i32::rem_euclid);
+        (Self::from_tile(tile), local.map(|i| i as usize))
+    }
+
+    /// The chunk containing a world position, tiles being centered on their coordinates.
+    pub fn from_position(pos: Vector2<f32>) -> Self {
+        Self::from_tile(pos.map(|i| i.round() as i32))
+    }
+
     pub fn to_tile_coords(&self) -> Vector2<i32> {
         self.0.zip(CHUNK_SIZE, |i, j| i * j)
     }
+
+    /// Distance in chunks along the farthest axis.
+    pub fn distance(&self, other: ChunkCoord) -> i32 {
+        let delta = self.0 - other.0;
+        delta.x.abs().max(delta.y.abs())
+    }
 }
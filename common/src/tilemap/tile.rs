@@ -2,8 +2,17 @@ use serde::{Deserialize, Serialize};
 
 use crate::utils::registry::RecordId;
 
+/// Left where a tile is broken.
+pub const BROKEN_TILE: &str = "dirt";
+/// How far from the center of a player tiles can be edited.
+pub const MAX_REACH: f32 = 6.;
+
 #[derive(Default, Serialize, Deserialize)]
-pub struct Tile {}
+pub struct Tile {
+    /// Whether players can place it
+    #[serde(default)]
+    pub placeable: bool,
+}
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct TileId(pub RecordId);
@@ -1,4 +1,5 @@
 pub mod handle;
 pub mod maths;
+pub mod noise;
 pub mod registry;
 pub mod timer;
@@ -0,0 +1,116 @@
+/// Mixes a seed and lattice point into well distributed bits, splitmix64 style.
+fn hash(seed: u64, x: i64, y: i64) -> u64 {
+    let mut h = seed
+        ^ (x as u64).wrapping_mul(0x9e3779b97f4a7c15)
+        ^ (y as u64).wrapping_mul(0xc2b2ae3d27d4eb4f);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+/// Uniform value in `[0, 1)` for a seed and lattice point.
+pub fn hash_unit(seed: u64, x: i64, y: i64) -> f64 {
+    (hash(seed, x, y) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+/// 2D gradient noise, roughly in `[-1, 1]`, the same for a seed on every platform.
+#[derive(Debug, Clone, Copy)]
+pub struct Perlin {
+    seed: u64,
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    fn gradient(&self, x: i64, y: i64, dx: f64, dy: f64) -> f64 {
+        // One of eight directions, scaled so the output spans about [-1, 1]
+        match hash(self.seed, x, y) & 7 {
+            0 => dx + dy,
+            1 => dx - dy,
+            2 => -dx + dy,
+            3 => -dx - dy,
+            4 => dx * std::f64::consts::SQRT_2,
+            5 => -dx * std::f64::consts::SQRT_2,
+            6 => dy * std::f64::consts::SQRT_2,
+            _ => -dy * std::f64::consts::SQRT_2,
+        }
+    }
+
+    pub fn get(&self, x: f64, y: f64) -> f64 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (dx, dy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let (u, v) = (fade(dx), fade(dy));
+        let bottom = lerp(
+            self.gradient(x0, y0, dx, dy),
+            self.gradient(x0 + 1, y0, dx - 1., dy),
+            u,
+        );
+        let top = lerp(
+            self.gradient(x0, y0 + 1, dx, dy - 1.),
+            self.gradient(x0 + 1, y0 + 1, dx - 1., dy - 1.),
+            u,
+        );
+        lerp(bottom, top, v)
+    }
+}
+
+/// Octaves of [`Perlin`] noise added together, each one finer and fainter than the last.
+#[derive(Debug, Clone, Copy)]
+pub struct Fbm {
+    noise: Perlin,
+    /// Features per unit of the coarsest octave
+    pub frequency: f64,
+    pub octaves: u32,
+    pub lacunarity: f64,
+    pub persistence: f64,
+}
+
+impl Fbm {
+    pub fn new(seed: u64, frequency: f64, octaves: u32) -> Self {
+        Self {
+            noise: Perlin::new(seed),
+            frequency,
+            octaves,
+            lacunarity: 2.,
+            persistence: 0.5,
+        }
+    }
+
+    /// Normalized to roughly `[-1, 1]` whatever the number of octaves.
+    pub fn get(&self, x: f64, y: f64) -> f64 {
+        let mut sum = 0.;
+        let mut amplitude = 1.;
+        let mut total = 0.;
+        let mut frequency = self.frequency;
+
+        for octave in 0..self.octaves {
+            // Offset octaves so their lattices do not line up at the origin
+            let offset = octave as f64 * 17.31;
+            sum += self
+                .noise
+                .get(x * frequency + offset, y * frequency + offset)
+                * amplitude;
+            total += amplitude;
+            amplitude *= self.persistence;
+            frequency *= self.lacunarity;
+        }
+
+        if total == 0. {
+            0.
+        } else {
+            sum / total
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, DeriveInput, LitInt};
+
+const DIRECTIONS: [&str; 3] = ["clientbound", "serverbound", "common"];
+const DELIVERIES: [&str; 3] = ["reliable_ordered", "unreliable_sequenced", "unreliable"];
+
+/// Implements `common::network::Packet` from a `#[packet(id = .., <direction>)]` attribute,
+/// where the direction is one of `clientbound`, `serverbound` or `common`.
+/// Packets after which frames are read differently also take a `changes_framing` flag.
+/// The delivery defaults to `reliable_ordered`, `unreliable_sequenced` and `unreliable`
+/// packets go over the datagram channel.
+#[proc_macro_derive(Packet, attributes(packet))]
+pub fn derive_packet(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_packet(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_packet(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let mut id = None;
+    let mut direction = None;
+    let mut changes_framing = false;
+    let mut delivery = None;
+
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("packet")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                id = Some(meta.value()?.parse::<LitInt>()?.base10_parse::<u16>()?);
+            } else if meta.path.is_ident("changes_framing") {
+                changes_framing = true;
+            } else if let Some(d) = DIRECTIONS.into_iter().find(|d| meta.path.is_ident(d)) {
+                if direction.replace(d).is_some() {
+                    return Err(meta.error("packet direction is specified twice"));
+                }
+            } else if let Some(d) = DELIVERIES.into_iter().find(|d| meta.path.is_ident(d)) {
+                if delivery.replace(d).is_some() {
+                    return Err(meta.error("packet delivery is specified twice"));
+                }
+            } else {
+                return Err(
+                    meta.error("expected `id = ..`, a direction, a delivery or `changes_framing`")
+                );
+            }
+            Ok(())
+        })?;
+    }
+
+    let ident = &input.ident;
+    let name = ident.to_string();
+
+    let id = id.ok_or_else(|| syn::Error::new_spanned(ident, "missing `#[packet(id = ..)]`"))?;
+    let direction = direction.ok_or_else(|| {
+        syn::Error::new_spanned(
+            ident,
+            "missing packet direction: `clientbound`, `serverbound` or `common`",
+        )
+    })?;
+
+    let delivery = delivery.unwrap_or("reliable_ordered");
+    if changes_framing && delivery != "reliable_ordered" {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "packets changing the framing must be `reliable_ordered`",
+        ));
+    }
+
+    // Catch a `Serverbound*` packet declared as clientbound and the like
+    for prefixed in ["clientbound", "serverbound"] {
+        let prefix = [&prefixed[..1].to_uppercase(), &prefixed[1..]].concat();
+        if name.starts_with(&prefix) && direction != prefixed {
+            return Err(syn::Error::new_spanned(
+                ident,
+                format!("packet `{name}` is named {prefixed} but declared {direction}"),
+            ));
+        }
+    }
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let variant = match direction {
+        "clientbound" => quote!(Clientbound),
+        "serverbound" => quote!(Serverbound),
+        _ => quote!(Common),
+    };
+    let delivery = match delivery {
+        "unreliable_sequenced" => quote!(UnreliableSequenced),
+        "unreliable" => quote!(Unreliable),
+        _ => quote!(ReliableOrdered),
+    };
+
+    let mut markers = TokenStream2::new();
+    if direction != "serverbound" {
+        markers.extend(quote! {
+            impl #impl_generics ::common::network::ClientboundPacket for #ident #ty_generics #where_clause {}
+        });
+    }
+    if direction != "clientbound" {
+        markers.extend(quote! {
+            impl #impl_generics ::common::network::ServerboundPacket for #ident #ty_generics #where_clause {}
+        });
+    }
+
+    Ok(quote! {
+        impl #impl_generics ::common::network::Packet for #ident #ty_generics #where_clause {
+            const ID: ::common::network::PacketId = #id;
+            const NAME: &'static str = #name;
+            const DIRECTION: ::common::network::PacketDirection =
+                ::common::network::PacketDirection::#variant;
+            const CHANGES_FRAMING: bool = #changes_framing;
+            const DELIVERY: ::common::network::Delivery =
+                ::common::network::Delivery::#delivery;
+        }
+
+        #markers
+    })
+}
@@ -0,0 +1,200 @@
+use std::net::SocketAddr;
+
+use cgmath::Vector2;
+use common::{
+    core::{movement::MAX_PENDING_INPUTS, spatial::Position, EntityKind},
+    logger::{debug, info, warn},
+    network::{
+        proto::{
+            extra::{CommonPing, DisconnectReason, ServerboundDisconnect},
+            handshake::{
+                ClientboundHandshakeRejected, ServerboundHandshake, ServerboundKeyExchange,
+                ServerboundStatusRequest,
+            },
+            login::{ClientboundLoginSuccess, ServerboundLoginStart},
+            play::{
+                ClientboundSpawnEntity, ServerboundBreakTile, ServerboundPlayerInputs,
+                ServerboundSetTile,
+            },
+        },
+        router::{ConnectionState, PacketRouter},
+    },
+    tilemap::tile::BROKEN_TILE,
+    utils::registry::RecordId,
+};
+
+use ecs::Entity;
+
+use crate::{network::remote::NetRemoteClient, GameServer};
+
+pub type ServerRouter = PacketRouter<GameServer, SocketAddr>;
+
+pub fn packet_router() -> ServerRouter {
+    use ConnectionState::*;
+
+    let mut router = PacketRouter::new();
+    router
+        .on(Handshake, handshake)
+        .on(Handshake, key_exchange)
+        .on(Handshake, status_request)
+        .on(Login, login_start)
+        .on(Play, player_inputs)
+        .on(Play, set_tile)
+        .on(Play, break_tile)
+        .on_each(&[Login, Play], ping)
+        .on_each(&[Handshake, Login, Play], disconnect);
+    router
+}
+
+fn handshake(server: &mut GameServer, addr: SocketAddr, packet: ServerboundHandshake) {
+    let network = &mut server.network;
+    if network.is_awaiting_key_exchange(&addr) {
+        network.kick(&addr, "Sent the handshake twice");
+        return;
+    }
+
+    match network.check_handshake(packet.protocol_version, packet.fingerprint) {
+        Ok(()) => network.complete_handshake(&addr),
+        Err(reason) => {
+            warn!("Rejected client {addr}: {reason}");
+            network.send_to([addr], &ClientboundHandshakeRejected { reason });
+            network.disconnect(&addr);
+        }
+    }
+}
+
+fn status_request(server: &mut GameServer, addr: SocketAddr, _: ServerboundStatusRequest) {
+    if server.network.is_awaiting_key_exchange(&addr) {
+        server
+            .network
+            .kick(&addr, "Queried the status during the handshake");
+        return;
+    }
+
+    let status = server.status();
+    server.network.answer_status(&addr, status);
+}
+
+fn key_exchange(server: &mut GameServer, addr: SocketAddr, packet: ServerboundKeyExchange) {
+    if let Err(reason) = server
+        .network
+        .complete_key_exchange(&addr, &packet.ephemeral_key)
+    {
+        server.network.kick(&addr, &reason);
+    }
+}
+
+fn login_start(server: &mut GameServer, addr: SocketAddr, packet: ServerboundLoginStart) {
+    let ServerboundLoginStart { username } = packet;
+    let GameServer {
+        network,
+        state,
+        settings,
+        ..
+    } = server;
+
+    if network.players().count() >= settings.max_players as usize {
+        info!("Client {addr} tried to log in as {username:?}, but the server is full");
+        network.disconnect_with(&addr, DisconnectReason::ServerFull);
+        return;
+    }
+
+    if network.is_username_taken(&username) {
+        info!("Client {addr} tried to log in as {username:?}, which is already connected");
+        network.disconnect_with(&addr, DisconnectReason::DuplicateUsername);
+        return;
+    }
+
+    info!("Client connected: {:?}", username);
+
+    let client_entity = state
+        .entities
+        .spawn()
+        .set(EntityKind::Player)
+        .set(Position(state.spawn))
+        .id();
+    let snapshot = state
+        .replication
+        .track(&mut state.entities.edit(client_entity).unwrap());
+
+    let Some(datagram_token) =
+        network.accept_connection(addr, NetRemoteClient::new(username, client_entity))
+    else {
+        state.entities.edit(client_entity).unwrap().despawn();
+        return;
+    };
+
+    network.send_to([addr], &ClientboundLoginSuccess { datagram_token });
+
+    // The player learns about its own entity first, other players and entities around it
+    // are spawned as they come into view
+    state.interest.add_player(addr, snapshot.entity);
+    network.send_to([addr], &ClientboundSpawnEntity { snapshot });
+}
+
+fn player_inputs(server: &mut GameServer, addr: SocketAddr, packet: ServerboundPlayerInputs) {
+    if packet.inputs.len() > MAX_PENDING_INPUTS {
+        server
+            .network
+            .kick(&addr, "Sent more inputs than a client keeps pending");
+        return;
+    }
+
+    server
+        .state
+        .apply_player_inputs(&addr, &packet.inputs, &mut server.network);
+}
+
+fn set_tile(server: &mut GameServer, addr: SocketAddr, packet: ServerboundSetTile) {
+    let placeable = server
+        .assets
+        .common
+        .tiles
+        .entries
+        .get_index(packet.tile.0)
+        .is_some_and(|(_, tile)| tile.placeable);
+    if !placeable {
+        server
+            .network
+            .kick(&addr, "Tried to place a tile that cannot be placed");
+        return;
+    }
+
+    edit_tile(server, addr, packet.pos, packet.tile);
+}
+
+fn break_tile(server: &mut GameServer, addr: SocketAddr, packet: ServerboundBreakTile) {
+    let broken = server.assets.common.tiles.get_id(BROKEN_TILE);
+    edit_tile(server, addr, packet.pos, broken);
+}
+
+/// Rejected edits are only logged, a player lagging behind can ask for tiles it no longer reaches.
+fn edit_tile(server: &mut GameServer, addr: SocketAddr, pos: Vector2<i32>, tile: RecordId) {
+    let GameServer {
+        network,
+        state,
+        settings,
+        ..
+    } = server;
+
+    let result = if settings.allow_building {
+        state.edit_tile(&addr, pos, tile, network)
+    } else {
+        Err("building is not allowed")
+    };
+    if let Err(reason) = result {
+        debug!("Rejected the tile edit of {addr} at {pos:?}: {reason}");
+    }
+}
+
+fn ping(server: &mut GameServer, addr: SocketAddr, CommonPing { time, .. }: CommonPing) {
+    if server.network.get_remote(&addr).is_some() {
+        let tick = server.state.tick;
+        server.network.send_to([addr], &CommonPing { time, tick });
+    }
+}
+
+fn disconnect(server: &mut GameServer, addr: SocketAddr, packet: ServerboundDisconnect) {
+    info!("Client disconnected: {:?} for reason: {:?}", addr, packet);
+    server.network.disconnect(&addr);
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
+
+use cgmath::{MetricSpace, Vector2};
+use common::network::replication::NetworkEntity;
+use ecs::EntityId;
+
+/// How far players see entities unless configured otherwise, in tiles.
+pub const DEFAULT_VIEW_RADIUS: f32 = 32.;
+/// Side of a grid cell in tiles, queries look at every cell overlapping the view radius.
+const CELL_SIZE: f32 = 16.;
+
+/// Entities in a grid cell, with their position.
+type Cell = Vec<(NetworkEntity, EntityId, Vector2<f32>)>;
+
+/// Replicated entities bucketed by the cell their position falls in.
+#[derive(Default)]
+pub struct SpatialGrid {
+    cells: HashMap<Vector2<i32>, Cell>,
+}
+
+impl SpatialGrid {
+    fn cell(pos: Vector2<f32>) -> Vector2<i32> {
+        Vector2::new(
+            (pos.x / CELL_SIZE).floor() as i32,
+            (pos.y / CELL_SIZE).floor() as i32,
+        )
+    }
+
+    /// Forgets every cell, the world is unbounded so cells left empty must not pile up.
+    pub fn clear(&mut self) {
+        self.cells.clear();
+    }
+
+    pub fn insert(&mut self, entity: NetworkEntity, id: EntityId, pos: Vector2<f32>) {
+        self.cells
+            .entry(Self::cell(pos))
+            .or_default()
+            .push((entity, id, pos));
+    }
+
+    /// Entities within `radius` of `center`.
+    pub fn query(
+        &self,
+        center: Vector2<f32>,
+        radius: f32,
+    ) -> impl Iterator<Item = (NetworkEntity, EntityId)> + '_ {
+        let min = Self::cell(center - Vector2::new(radius, radius));
+        let max = Self::cell(center + Vector2::new(radius, radius));
+
+        (min.x..=max.x)
+            .flat_map(move |x| (min.y..=max.y).map(move |y| Vector2::new(x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .filter(move |(_, _, pos)| pos.distance2(center) <= radius * radius)
+            .map(|(entity, id, _)| (*entity, *id))
+    }
+}
+
+/// What a player's view gained and lost since the last update.
+pub struct InterestChanges {
+    pub entered: Vec<(NetworkEntity, EntityId)>,
+    pub left: Vec<NetworkEntity>,
+}
+
+/// Tracks which replicated entities each player can see.
+pub struct InterestManager {
+    pub view_radius: f32,
+    grid: SpatialGrid,
+    visible: HashMap<SocketAddr, HashSet<NetworkEntity>>,
+}
+
+impl InterestManager {
+    pub fn new(view_radius: f32) -> Self {
+        Self {
+            view_radius,
+            grid: SpatialGrid::default(),
+            visible: HashMap::new(),
+        }
+    }
+
+    /// Starts tracking a player, who already knows about its own entity.
+    pub fn add_player(&mut self, addr: SocketAddr, entity: NetworkEntity) {
+        self.visible.insert(addr, HashSet::from([entity]));
+    }
+
+    pub fn remove_player(&mut self, addr: &SocketAddr) {
+        self.visible.remove(addr);
+    }
+
+    /// Replaces the entities of the grid with where they are this tick.
+    pub fn rebuild_grid(
+        &mut self,
+        entities: impl IntoIterator<Item = (NetworkEntity, EntityId, Vector2<f32>)>,
+    ) {
+        self.grid.clear();
+        for (entity, id, pos) in entities {
+            self.grid.insert(entity, id, pos);
+        }
+    }
+
+    pub fn can_see(&self, addr: &SocketAddr, entity: NetworkEntity) -> bool {
+        self.visible
+            .get(addr)
+            .is_some_and(|visible| visible.contains(&entity))
+    }
+
+    /// Recomputes the view of a player standing at `pos` from the grid.
+    /// The player's own entity is always visible, wherever the grid puts it.
+    pub fn update_player(
+        &mut self,
+        addr: SocketAddr,
+        own: NetworkEntity,
+        pos: Vector2<f32>,
+    ) -> InterestChanges {
+        let visible = self.visible.entry(addr).or_default();
+        let mut seen = HashSet::from([own]);
+        let mut entered = Vec::new();
+
+        for (entity, id) in self.grid.query(pos, self.view_radius) {
+            if seen.insert(entity) && !visible.contains(&entity) {
+                entered.push((entity, id));
+            }
+        }
+
+        let left = visible.difference(&seen).copied().collect();
+        *visible = seen;
+        InterestChanges { entered, left }
+    }
+}
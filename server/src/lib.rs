@@ -1,135 +1,369 @@
 pub mod assets;
+pub mod handlers;
+pub mod interest;
 pub mod network;
+pub mod replication;
+pub mod save;
 pub mod state;
+pub mod streaming;
+pub mod world;
 
-use std::{net::SocketAddr, time::Instant};
+use std::{
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
 use assets::ServerAssets;
-use cgmath::{Vector2, Zero};
 use common::{
-    core::{spatial::Position, EntityKind},
-    logger::info,
-    network::proto::{
-        extra::{CommonPing, ServerboundDisconnect},
-        login::{ClientboundLoginSuccess, ServerboundLoginStart},
-        play::{ClientboundRemoveEntity, ClientboundSpawnEntity, ServerboundSetPlayerPos},
-        SyncComponentSelection,
+    core::tick::{Tick, TICK_DURATION},
+    logger::{error, info, warn},
+    network::{
+        lan::{LanAnnouncement, LanAnnouncer},
+        loopback::{self, LoopbackConnector, LoopbackStream},
+        proto::{handshake::ServerStatus, PROTOCOL_VERSION},
+        router::{ConnectionState, RouteError},
+        DEFAULT_SERVER_ADDRESS, DEFAULT_WEBSOCKET_ADDRESS,
     },
-    utils::timer::Timer,
+    tilemap::generation,
 };
 use ecs::Entity;
-use network::{remote::NetRemoteClient, NetworkServer};
+use handlers::{packet_router, ServerRouter};
+use network::NetworkServer;
 use state::ServerState;
 
-pub const SERVER_UPS_CAP: f32 = 60.;
+/// Ticks the server runs back to back to catch up after a stall, older ones are skipped.
+pub const MAX_CATCH_UP_TICKS: u32 = 5;
+/// How often the world is saved while the server runs, it is also saved on shutdown.
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+pub const DEFAULT_WORLD_PATH: &str = "world";
+/// World of the server started by the client to play alone.
+pub const LOCAL_WORLD_PATH: &str = "saves/local";
 
 pub fn run_server() {
-    let mut server = GameServer::new();
+    let mut server = GameServer::open(DEFAULT_WORLD_PATH).expect("Failed to load the world");
+    server
+        .network
+        .listen_tcp(DEFAULT_SERVER_ADDRESS)
+        .expect("Failed to bind server address");
+    server
+        .network
+        .listen_websocket(DEFAULT_WEBSOCKET_ADDRESS)
+        .unwrap_or_else(|e| warn!("Websocket clients cannot connect: {e}"));
+
+    let port = DEFAULT_SERVER_ADDRESS
+        .parse::<SocketAddr>()
+        .expect("Invalid default server address")
+        .port();
+    server
+        .announce_on_lan(port)
+        .unwrap_or_else(|e| warn!("The server cannot be discovered on the local network: {e}"));
+
+    let stop = server.stop_signal();
+    ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))
+        .unwrap_or_else(|e| warn!("The server cannot be stopped gracefully: {e}"));
+    read_console(server.stop_signal(), server.stats_signal())
+        .unwrap_or_else(|e| warn!("The server cannot read commands from its console: {e}"));
+    server.run();
+}
+
+/// Stops the server once `stop` is typed in its console, logs its stats on `stats`.
+fn read_console(stop: Arc<AtomicBool>, stats: Arc<AtomicBool>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("console".to_string())
+        .spawn(move || {
+            for line in io::stdin().lines() {
+                let Ok(line) = line else {
+                    return;
+                };
+                match line.trim() {
+                    "" => {}
+                    "stop" => {
+                        stop.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    "stats" => stats.store(true, Ordering::Relaxed),
+                    command => warn!(
+                        "Unknown command {command:?}, `stop` saves the world and stops the server, `stats` logs network and rate limit stats"
+                    ),
+                }
+            }
+        })?;
+    Ok(())
+}
 
-    loop {
-        server.update();
+/// A server running on a background thread, only reachable in memory.
+pub struct LocalServer {
+    connector: LoopbackConnector,
+    thread: JoinHandle<()>,
+}
+
+impl LocalServer {
+    pub fn start() -> Self {
+        let (connector, listener) = loopback::listener();
+        let thread = thread::Builder::new()
+            .name("local server".to_string())
+            .spawn(move || {
+                let mut server = GameServer::open(LOCAL_WORLD_PATH).unwrap_or_else(|e| {
+                    warn!("Failed to load the local world, this one will not be saved: {e}");
+                    GameServer::new()
+                });
+                server.network.add_listener(listener);
+                server.run();
+            })
+            .expect("Failed to spawn local server thread");
 
-        std::time::Duration::from_secs_f32(1. / SERVER_UPS_CAP)
-            .checked_sub(server.timer.last_update.elapsed())
-            .map(|d| std::thread::sleep(d));
+        Self { connector, thread }
+    }
+
+    pub fn connect(&self) -> io::Result<LoopbackStream> {
+        self.connector.connect()
+    }
+
+    /// Waits for the server to stop, once its last connection is closed.
+    pub fn stop(self) {
+        drop(self.connector);
+        if self.thread.join().is_err() {
+            warn!("Local server thread panicked");
+        }
+    }
+}
+
+/// How the server presents itself to players.
+pub struct ServerSettings {
+    pub name: String,
+    /// Message of the day, shown in server lists
+    pub motd: String,
+    pub max_players: u32,
+    /// Whether players may place and break tiles
+    pub allow_building: bool,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            name: "Underworld server".to_string(),
+            motd: "Welcome to the underworld".to_string(),
+            max_players: 16,
+            allow_building: true,
+        }
     }
 }
 
 pub struct GameServer {
+    pub settings: ServerSettings,
+    router: Rc<ServerRouter>,
     assets: ServerAssets,
-    timer: Timer,
+    /// Announces the server with the port of its stream listener
+    lan: Option<(LanAnnouncer, u16)>,
     network: NetworkServer,
+    /// Set from other threads to make [`GameServer::run`] return
+    stop: Arc<AtomicBool>,
+    /// Set from other threads to log the stats at the end of the running tick
+    stats_requested: Arc<AtomicBool>,
 
     state: ServerState,
 }
 
 impl GameServer {
+    /// A server with a new world that is never saved.
     pub fn new() -> Self {
         let assets = ServerAssets::load();
-        let timer = Timer::new();
-        let network = NetworkServer::new();
-        let state = ServerState::new(&assets);
+        let state = ServerState::new(&assets, generation::random_seed());
+        Self::with_state(assets, state)
+    }
 
+    /// A server playing the world saved in `dir`, created there if there is none.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let assets = ServerAssets::load();
+        let state = ServerState::open(&assets, dir)?;
+        Ok(Self::with_state(assets, state))
+    }
+
+    fn with_state(assets: ServerAssets, state: ServerState) -> Self {
+        let network = NetworkServer::new();
         Self {
+            settings: ServerSettings::default(),
+            router: Rc::new(packet_router()),
             assets,
-            timer,
+            lan: None,
             network,
+            stop: Arc::new(AtomicBool::new(false)),
+            stats_requested: Arc::new(AtomicBool::new(false)),
             state,
         }
     }
 
-    pub fn update(&mut self) {
-        let _dt = self.timer.update_dt();
-
-        self.network.listen_for_connections();
+    /// Runs ticks at a fixed rate until the server is stopped or cannot be reached anymore,
+    /// then shuts it down.
+    pub fn run(&mut self) {
+        let mut next_tick = Instant::now();
+        while !self.network.is_unreachable() && !self.stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now < next_tick {
+                thread::sleep(next_tick - now);
+                continue;
+            }
 
-        self.network.handle_packets(|network, addr, packet| {
-            if let Some(CommonPing { time }) = packet.try_decode::<CommonPing>() {
-                if let Some(NetRemoteClient { last_packet, .. }) = network.get_remote_mut(&addr) {
-                    *last_packet = Instant::now();
-                    network.send_to([addr], &CommonPing { time });
-                }
+            let behind = ((now - next_tick).as_secs_f64() / TICK_DURATION.as_secs_f64()) as u32;
+            if behind > MAX_CATCH_UP_TICKS {
+                warn!("Server is {behind} ticks behind, skipping them");
+                next_tick = now;
             }
 
-            if let Some(packet) = packet.try_decode::<ServerboundDisconnect>() {
-                info!("Client disconnected: {:?} for reason: {:?}", addr, packet);
-                network.disconnect(&addr);
-                return;
+            let tick = self.state.tick;
+            self.update();
+            next_tick += TICK_DURATION;
+
+            let elapsed = now.elapsed();
+            if elapsed > TICK_DURATION {
+                warn!(
+                    "Tick {} took {}ms, longer than the {}ms it should",
+                    tick.0,
+                    elapsed.as_millis(),
+                    TICK_DURATION.as_millis()
+                );
             }
+        }
+
+        self.shutdown();
+    }
+
+    /// Setting it stops the server at the end of the running tick.
+    pub fn stop_signal(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Setting it logs the network and rate limit stats at the end of the running tick.
+    pub fn stats_signal(&self) -> Arc<AtomicBool> {
+        self.stats_requested.clone()
+    }
+
+    fn log_stats(&self) {
+        info!(
+            "{}\nRejected {} packets sent in the wrong state\n{}",
+            self.network.stats(),
+            self.network.rejected_packets(),
+            self.network.rate_limit_counters(),
+        );
+    }
+
+    /// Announces the server on the local network, players join it on `port`.
+    pub fn announce_on_lan(&mut self, port: u16) -> io::Result<()> {
+        self.lan = Some((LanAnnouncer::broadcast()?, port));
+        Ok(())
+    }
+
+    pub fn status(&self) -> ServerStatus {
+        let players = self
+            .network
+            .player_names()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        ServerStatus {
+            name: self.settings.name.clone(),
+            motd: self.settings.motd.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            online_players: players.len() as u32,
+            max_players: self.settings.max_players,
+            players,
+        }
+    }
+
+    /// Simulates one tick.
+    pub fn update(&mut self) {
+        self.network.listen_for_connections();
 
-            if network.is_connecting(&addr) {
-                if let Some(ServerboundLoginStart { username }) = packet.try_decode() {
-                    connect_player(network, addr, username, &mut self.state);
+        let router = self.router.clone();
+        for (addr, packet) in self.network.poll_packets() {
+            let Some(state) = self
+                .network
+                .connection_state(&addr)
+                .filter(|state| *state != ConnectionState::Closing)
+            else {
+                continue; // Disconnected by an earlier packet
+            };
+
+            let name = self
+                .network
+                .protocol()
+                .info(packet.id)
+                .map_or("unknown packet", |info| info.name);
+            match router.route(self, state, addr, packet) {
+                Ok(()) => {}
+                Err(RouteError::Unhandled { .. }) if state == ConnectionState::Handshake => {
+                    self.network
+                        .kick(&addr, &format!("Sent {name} before the handshake"));
+                }
+                Err(RouteError::Unhandled { .. }) => {
+                    self.network.reject_packet(&addr, name);
                 }
-            } else {
-                if let Some(ServerboundSetPlayerPos { pos }) = packet.try_decode() {
-                    self.state.set_player_position(&addr, pos, network);
+                Err(e) => {
+                    self.network
+                        .kick(&addr, &format!("Failed to handle {name}: {e}"));
                 }
             }
-        });
+        }
 
-        self.network.handle_disconnections(|network, addr, client| {
+        self.network.handle_disconnections(|_, addr, client| {
             info!("Client disconnected: {:?}", addr);
 
+            // Players that could see it are told once their view is updated
             self.state.entities.edit(client.entity).unwrap().despawn();
-            network.broadcast(&ClientboundRemoveEntity {
-                entity: client.entity.into(),
-            });
+            self.state.interest.remove_player(&addr);
         });
 
         // Send data, update server state
+        self.state.update_world(&self.network);
+        self.state.stream_chunks(&mut self.network);
+        self.state.replicate(&mut self.network);
+
+        if let Some((announcer, port)) = &mut self.lan {
+            announcer.announce(&LanAnnouncement {
+                name: self.settings.name.clone(),
+                port: *port,
+                protocol_version: PROTOCOL_VERSION,
+                online_players: self.network.players().count() as u32,
+                max_players: self.settings.max_players,
+            });
+        }
 
         self.network.flush();
+        self.state.tick = self.state.tick.next();
+
+        let autosave = Tick::from_secs(AUTOSAVE_INTERVAL.as_secs_f64()).0;
+        if self.state.tick.0.is_multiple_of(autosave) {
+            self.state
+                .save()
+                .unwrap_or_else(|e| error!("Failed to save the world: {e}"));
+        }
+
+        if self.stats_requested.swap(false, Ordering::Relaxed) {
+            self.log_stats();
+        }
     }
-}
 
-fn connect_player(
-    network: &mut NetworkServer,
-    addr: SocketAddr,
-    username: String,
-    state: &mut ServerState,
-) {
-    info!("Client connected: {:?}", username);
-
-    // Save the ecs state before spawning the player
-    let ecs_state = state.entities.save();
-    let terrain = state.terrain.clone();
-
-    let client_entity = state
-        .entities
-        .spawn()
-        .set(EntityKind::Player)
-        .set(Position(Vector2::zero()))
-        .id();
-
-    network.accept_connection(addr, NetRemoteClient::new(username, client_entity));
-
-    network.send_to([addr], &ClientboundLoginSuccess { terrain, ecs_state });
-
-    network.broadcast(&ClientboundSpawnEntity {
-        entity: client_entity.into(),
-        state: state
-            .entities
-            .save_entity::<SyncComponentSelection>(client_entity),
-    });
+    /// Tells every client the server is going away and waits for that to be sent.
+    pub fn shutdown(&mut self) {
+        info!("Shutting down server");
+
+        self.network.shutdown();
+        while self.network.has_connections() {
+            self.network.flush();
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        self.state
+            .save()
+            .unwrap_or_else(|e| error!("Failed to save the world: {e}"));
+
+        self.log_stats();
+    }
 }
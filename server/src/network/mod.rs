@@ -1,125 +1,338 @@
 use std::{
     collections::HashMap,
-    io::{ErrorKind, Write},
-    iter,
-    net::{SocketAddr, TcpListener, TcpStream},
-    rc::Rc,
-    time::Duration,
+    io::{self, ErrorKind},
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
 };
 
 use common::{
     logger::{info, warn},
-    network::{proto::network_protocol, AnyPacket, Protocol},
+    network::{
+        connection::{Connection, DEFAULT_MAX_SEND_BACKLOG},
+        crypto::{HandshakeTranscript, KeyExchange, PublicKeyBytes, Role, ServerIdentity},
+        datagram::{self, DatagramChannel, SessionToken, MAX_DATAGRAM_SIZE},
+        proto::{
+            extra::{ClientboundDisconnect, DisconnectReason},
+            handshake::{
+                ClientboundHandshakeSuccess, ClientboundStatusResponse, EncryptionOffer,
+                ServerStatus,
+            },
+            network_protocol, PROTOCOL_VERSION,
+        },
+        router::ConnectionState,
+        stats::NetworkStats,
+        transport::{self, TransportListener},
+        websocket::WebSocketListener,
+        AnyPacket, ClientboundPacket, Protocol, DEFAULT_COMPRESSION_THRESHOLD,
+    },
 };
+use rate_limit::{LimitAction, RateLimitCounters, RateLimiter, RateLimits, ServerRateLimiter};
 use remote::NetRemoteClient;
-use serde::Serialize;
 
+pub mod rate_limit;
 pub mod remote;
+
+/// Packets a client may send in the wrong state before being kicked.
+pub const MAX_STATE_VIOLATIONS: u32 = 8;
+/// How long a closing connection may take to flush its last packets.
+const CLOSING_TIMEOUT: Duration = Duration::from_secs(2);
+/// Where the server keeps the key clients pin on first use.
+pub const IDENTITY_KEY_PATH: &str = "server_identity.key";
+
+struct ClientConnection {
+    connection: Connection,
+    state: ConnectionState,
+    violations: u32,
+    /// When the connection was accepted or last received a packet
+    last_read: Instant,
+    closing_since: Option<Instant>,
+    pending_exchange: Option<KeyExchange>,
+    /// Opened at login, used once the client sent a first datagram from `datagram_addr`
+    datagrams: Option<DatagramChannel>,
+    datagram_addr: Option<SocketAddr>,
+    remote: Option<NetRemoteClient>,
+    limiter: RateLimiter,
+}
+
+impl ClientConnection {
+    fn new(connection: Connection, limits: &RateLimits) -> Self {
+        Self {
+            connection,
+            state: ConnectionState::Handshake,
+            violations: 0,
+            last_read: Instant::now(),
+            closing_since: None,
+            pending_exchange: None,
+            datagrams: None,
+            datagram_addr: None,
+            remote: None,
+            limiter: RateLimiter::new(limits),
+        }
+    }
+
+    fn stats(&self) -> NetworkStats {
+        let mut stats = *self.connection.stats();
+        if let Some(channel) = &self.datagrams {
+            stats.merge(channel.stats());
+        }
+        stats
+    }
+}
+
 pub struct NetworkServer {
     protocol: Protocol,
     timeout: Duration,
-    server: TcpListener,
-    connecting_clients: HashMap<SocketAddr, TcpStream>,
+    max_send_backlog: usize,
+    compression_threshold: Option<u32>,
+    /// Sessions are encrypted when the server has an identity
+    identity: Option<ServerIdentity>,
+    listeners: Vec<Box<dyn TransportListener>>,
+    /// Bound to the same address as the tcp listener, if any
+    datagram_socket: Option<UdpSocket>,
+    connections: HashMap<SocketAddr, ClientConnection>,
+    /// Stream address of the client owning each datagram token
+    tokens: HashMap<SessionToken, SocketAddr>,
     disconnected_clients: HashMap<SocketAddr, NetRemoteClient>,
-    clients: HashMap<SocketAddr, (TcpStream, NetRemoteClient)>,
+    rejected_packets: u64,
+    rate_limits: RateLimits,
+    server_limiter: ServerRateLimiter,
+    rate_limited: RateLimitCounters,
+    closed_stats: NetworkStats,
 }
 
 impl NetworkServer {
+    /// A server without listeners, see [`NetworkServer::listen_tcp`] and
+    /// [`NetworkServer::add_listener`].
     pub fn new() -> Self {
-        let server_address = "127.0.0.1:8888";
         let timeout = Duration::from_secs(5);
 
-        let server = TcpListener::bind(server_address).expect("Failed to bind/configure listener");
-        server
-            .set_nonblocking(true)
-            .expect("Failed to set listener to non-blocking");
-        info!("Server listening on {server_address}");
+        let identity = ServerIdentity::load_or_generate(IDENTITY_KEY_PATH)
+            .inspect_err(|e| {
+                warn!("Failed to load server identity, sessions will not be encrypted: {e}")
+            })
+            .ok();
 
-        let connecting_clients = HashMap::new();
+        let connections = HashMap::new();
         let disconnected_clients = HashMap::new();
-        let clients = HashMap::new();
+        let rate_limits = RateLimits::default();
 
         Self {
             protocol: network_protocol(),
             timeout,
-            server,
-            connecting_clients,
+            max_send_backlog: DEFAULT_MAX_SEND_BACKLOG,
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            identity,
+            listeners: Vec::new(),
+            datagram_socket: None,
+            connections,
+            tokens: HashMap::new(),
             disconnected_clients,
-            clients,
+            rejected_packets: 0,
+            server_limiter: ServerRateLimiter::new(&rate_limits),
+            rate_limits,
+            rate_limited: RateLimitCounters::default(),
+            closed_stats: NetworkStats::default(),
         }
     }
 
+    /// Accepts streams over tcp and datagrams over udp, both on `address`.
+    pub fn listen_tcp(&mut self, address: &str) -> io::Result<()> {
+        let listener = transport::listen_tcp(address)?;
+        let datagram_socket = UdpSocket::bind(address)?;
+        datagram_socket.set_nonblocking(true)?;
+
+        info!("Server listening on {address}");
+        self.listeners.push(Box::new(listener));
+        self.datagram_socket = Some(datagram_socket);
+        Ok(())
+    }
+
+    /// Accepts streams carried by websockets on `address`, without datagrams.
+    pub fn listen_websocket(&mut self, address: &str) -> io::Result<()> {
+        let listener = WebSocketListener::bind(address)?;
+
+        info!("Server listening for websockets on {address}");
+        self.listeners.push(Box::new(listener));
+        Ok(())
+    }
+
+    pub fn add_listener(&mut self, listener: impl TransportListener + 'static) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    /// Whether no client is connected and none can connect anymore.
+    pub fn is_unreachable(&self) -> bool {
+        self.connections.is_empty() && self.listeners.iter().all(|l| l.is_closed())
+    }
+
     pub fn listen_for_connections(&mut self) {
-        loop {
-            match self.server.accept() {
-                Ok((socket, addr)) => {
-                    if !self.clients.contains_key(&addr) {
-                        self.connecting_clients.insert(addr, socket);
-                    } else {
-                        warn!(
-                            "Client {addr} tried to connect while already waiting for a connection"
-                        );
-                        self.disconnect(&addr);
+        let mut accepted = Vec::new();
+        for listener in &mut self.listeners {
+            loop {
+                match listener.accept() {
+                    Ok(Some(connection)) => accepted.push(connection),
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Failed to accept connection: {e:?}");
+                        break;
                     }
                 }
-                Err(error) => {
-                    if error.kind() == std::io::ErrorKind::WouldBlock {
-                        return; // Finish handling packets
-                    }
+            }
+        }
 
-                    warn!("Client disconnected with error: {:?}", error);
-                }
+        for (transport, addr) in accepted {
+            if !self.connections.contains_key(&addr) {
+                let connection = Connection::new(transport);
+                self.connections
+                    .insert(addr, ClientConnection::new(connection, &self.rate_limits));
+            } else {
+                warn!("Client {addr} tried to connect while already waiting for a connection");
+                self.disconnect(&addr);
             }
         }
     }
 
-    pub fn handle_packets(&mut self, mut handler: impl FnMut(&mut Self, SocketAddr, AnyPacket)) {
-        let incoming = self
-            .connecting_clients
-            .iter()
-            .chain(
-                self.clients
-                    .iter()
-                    .map(|(addr, (socket, _))| (addr, socket)),
-            )
-            .map(|(addr, socket)| {
-                (
-                    iter::from_fn(|| match self.protocol.decode(socket) {
-                        Err(common::network::ErrorKind::Io(e))
-                            if e.kind() == ErrorKind::WouldBlock =>
-                        {
-                            None
-                        }
-                        r => Some(r),
-                    })
-                    .collect::<Result<Box<_>, common::network::ErrorKind>>(),
-                    *addr,
-                )
+    /// Receives the packets of every client, disconnecting clients whose stream failed.
+    /// Closing connections are not read from anymore.
+    pub fn poll_packets(&mut self) -> Vec<(SocketAddr, AnyPacket)> {
+        let protocol = &self.protocol;
+        let mut packets = Vec::new();
+        let failed = self
+            .connections
+            .iter_mut()
+            .filter(|(_, client)| client.state != ConnectionState::Closing)
+            .filter_map(|(addr, client)| {
+                let mut received = Vec::new();
+                let result = client.connection.receive(protocol, &mut received);
+                if !received.is_empty() {
+                    client.last_read = Instant::now();
+                }
+                packets.extend(received.into_iter().map(|packet| (*addr, packet)));
+                result.err().map(|e| (*addr, e))
             })
             .collect::<Box<_>>();
 
-        for (packets, addr) in incoming {
-            match packets {
-                Err(common::network::ErrorKind::Io(e))
-                    if [
-                        ErrorKind::ConnectionAborted,
-                        ErrorKind::ConnectionReset,
-                        ErrorKind::ConnectionRefused,
-                        ErrorKind::UnexpectedEof,
-                    ]
-                    .contains(&e.kind()) =>
-                {
-                    info!("Client {addr} disconnected because of network error: {e:?}");
-                    self.disconnect(&addr);
+        for (addr, e) in failed {
+            if e.is_disconnect() {
+                info!("Client {addr} disconnected because of network error: {e:?}");
+            } else {
+                warn!("Dropping client {addr}, failed to receive packets: {e}");
+            }
+            self.disconnect(&addr);
+        }
+
+        self.poll_datagrams(&mut packets);
+
+        let mut allowed = Vec::with_capacity(packets.len());
+        for (addr, packet) in packets {
+            if self.check_rate_limits(&addr, &packet) {
+                allowed.push((addr, packet));
+            }
+        }
+        allowed
+    }
+
+    /// Returns whether the packet should be handled, kicking clients flooding the server.
+    fn check_rate_limits(&mut self, addr: &SocketAddr, packet: &AnyPacket) -> bool {
+        let Some(client) = self
+            .connections
+            .get_mut(addr)
+            .filter(|client| client.state != ConnectionState::Closing)
+        else {
+            return false; // Kicked by an earlier packet
+        };
+        // Packets over the limits of their client do not count against the server's
+        let violation = client
+            .limiter
+            .check(&self.rate_limits, packet.id, packet.data.len())
+            .or_else(|| {
+                let violation = self
+                    .server_limiter
+                    .check(&self.rate_limits, packet.data.len());
+                if violation.is_some() {
+                    self.rate_limited.server_limited += 1;
                 }
+                violation
+            });
+        let Some(violation) = violation else {
+            return true;
+        };
+        self.rate_limited.record(violation.action);
+
+        let name = self
+            .protocol
+            .info(packet.id)
+            .map_or("unknown packet", |info| info.name);
+        if violation.action == LimitAction::Kick {
+            self.kick(
+                addr,
+                &format!("Went over the {} rate limit", violation.limit),
+            );
+            return false;
+        }
+
+        if violation.first {
+            let username = client.remote.as_ref().map(|remote| &remote.username);
+            warn!(
+                "Client {addr} ({username:?}) went over the {} rate limit with {name}, {}",
+                violation.limit,
+                match violation.action {
+                    LimitAction::Drop => "dropping packets",
+                    _ => "still handling packets",
+                }
+            );
+        }
+        violation.action == LimitAction::Warn
+    }
+
+    /// Receives pending datagrams, invalid ones are dropped since anyone can send them.
+    fn poll_datagrams(&mut self, packets: &mut Vec<(SocketAddr, AnyPacket)>) {
+        let Some(socket) = &self.datagram_socket else {
+            return;
+        };
+
+        let mut buffer = [0; MAX_DATAGRAM_SIZE];
+        loop {
+            let (size, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                // Reported by some platforms when a previous datagram was not delivered
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
                 Err(e) => {
-                    warn!("Failed to decode packets from client: {e:?}");
+                    warn!("Failed to receive datagrams: {e}");
+                    return;
                 }
-                Ok(packets) => {
-                    for packet in packets {
-                        handler(self, addr, packet);
+            };
+            let datagram = &buffer[..size];
+
+            let Some(addr) = datagram::read_token(datagram)
+                .and_then(|token| self.tokens.get(&token))
+                .copied()
+            else {
+                continue;
+            };
+            let Some(client) = self
+                .connections
+                .get_mut(&addr)
+                .filter(|client| client.state == ConnectionState::Play)
+            else {
+                continue;
+            };
+            let Some(channel) = &mut client.datagrams else {
+                continue;
+            };
+
+            let mut received = Vec::new();
+            match channel.receive(&self.protocol, datagram, &mut received) {
+                Ok(newest) => {
+                    // Replays of older datagrams from elsewhere cannot redirect ours
+                    if newest {
+                        client.datagram_addr = Some(from);
                     }
+                    client.last_read = Instant::now();
+                    packets.extend(received.into_iter().map(|packet| (addr, packet)));
                 }
+                Err(e) => warn!("Dropped datagram from {from} for client {addr}: {e}"),
             }
         }
     }
@@ -128,17 +341,17 @@ impl NetworkServer {
         &mut self,
         mut handler: impl FnMut(&mut Self, SocketAddr, NetRemoteClient),
     ) {
-        //TODO: Maybe need to be fixed and also no need to run this every update
-        for (addr, last_packet) in &self
-            .clients
+        // Closing connections have their own timeout, see `flush`
+        let timed_out = self
+            .connections
             .iter()
-            .map(|(&addr, &(_, NetRemoteClient { last_packet, .. }))| (addr, last_packet))
-            .collect::<Box<_>>()
-        {
-            if last_packet.elapsed() > self.timeout {
-                info!("Client {addr:?} timed out");
-                self.disconnect(addr);
-            }
+            .filter(|(_, client)| client.state != ConnectionState::Closing)
+            .filter(|(_, client)| client.last_read.elapsed() > self.timeout)
+            .map(|(addr, _)| *addr)
+            .collect::<Box<_>>();
+        for addr in timed_out.iter() {
+            info!("Client {addr:?} timed out");
+            self.disconnect_with(addr, DisconnectReason::TimedOut);
         }
 
         for (addr, client) in std::mem::take(&mut self.disconnected_clients) {
@@ -146,75 +359,378 @@ impl NetworkServer {
         }
     }
 
-    pub fn is_connecting(&self, addr: &SocketAddr) -> bool {
-        self.connecting_clients.contains_key(addr)
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
     }
 
-    pub fn accept_connection(&mut self, addr: SocketAddr, profile: NetRemoteClient) {
-        if let Some(socket) = self.connecting_clients.remove(&addr) {
-            self.clients.insert(addr, (socket, profile));
+    pub fn connection_state(&self, addr: &SocketAddr) -> Option<ConnectionState> {
+        self.connections.get(addr).map(|client| client.state)
+    }
+
+    /// Total number of packets rejected for being sent in the wrong state.
+    pub fn rejected_packets(&self) -> u64 {
+        self.rejected_packets
+    }
+
+    /// How often clients went over their rate limits, by action taken.
+    pub fn rate_limit_counters(&self) -> RateLimitCounters {
+        self.rate_limited
+    }
+
+    /// Applies to every connection from now on.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.server_limiter = ServerRateLimiter::new(&limits);
+        self.rate_limits = limits;
+    }
+
+    /// Checks the client's protocol against ours, returning a reason on mismatch.
+    pub fn check_handshake(&self, protocol_version: u32, fingerprint: u64) -> Result<(), String> {
+        if protocol_version != PROTOCOL_VERSION {
+            return Err(format!(
+                "Protocol version mismatch: server is on version {PROTOCOL_VERSION}, client is on version {protocol_version}"
+            ));
+        }
+
+        if fingerprint != self.protocol.fingerprint() {
+            return Err(format!(
+                "Packet table mismatch: server fingerprint is {:016x}, client fingerprint is {fingerprint:016x}, the builds differ",
+                self.protocol.fingerprint()
+            ));
         }
+
+        Ok(())
     }
 
-    fn send_data_to(&mut self, addrs: impl IntoIterator<Item = SocketAddr>, data: Rc<[u8]>) {
-        for addr in addrs {
-            self.clients
-                .get_mut(&addr)
-                .unwrap()
-                .0
-                .write_all(&data)
-                .unwrap_or_else(|e| {
-                    warn!("Failed to write data to {addr:?} client: {e:?}");
-                })
+    fn transition(&mut self, addr: &SocketAddr, from: ConnectionState, to: ConnectionState) {
+        match self.connections.get_mut(addr) {
+            Some(client) if client.state == from => client.state = to,
+            Some(client) => warn!(
+                "Client {addr} cannot go from {from:?} to {to:?}, it is in the {:?} state",
+                client.state
+            ),
+            None => {}
         }
     }
 
-    pub fn send_to<'a, T: Serialize + 'static>(
+    /// Answers a status query and closes the connection, the client never logs in.
+    pub fn answer_status(&mut self, addr: &SocketAddr, status: ServerStatus) {
+        self.transition(addr, ConnectionState::Handshake, ConnectionState::Status);
+        self.send_to([*addr], &ClientboundStatusResponse { status });
+        self.disconnect(addr);
+    }
+
+    /// Names of the logged in players.
+    pub fn player_names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.players().map(|(_, remote)| remote.username.as_str())
+    }
+
+    /// Enables compression from here on, and offers encryption if the server has an identity.
+    /// Without encryption the client moves straight to the login state.
+    pub fn complete_handshake(&mut self, addr: &SocketAddr) {
+        let compression_threshold = self.compression_threshold;
+        // The client handshake matched ours, see `check_handshake`
+        let transcript = HandshakeTranscript {
+            protocol_version: PROTOCOL_VERSION,
+            fingerprint: self.protocol.fingerprint(),
+            compression_threshold,
+        };
+        let exchange = self.identity.as_ref().map(|_| KeyExchange::new());
+        let encryption =
+            self.identity
+                .as_ref()
+                .zip(exchange.as_ref())
+                .map(|(identity, exchange)| EncryptionOffer {
+                    identity_key: identity.public_key(),
+                    ephemeral_key: exchange.public_key(),
+                    signature: identity.sign_exchange(&transcript, &exchange.public_key()),
+                });
+
+        self.send_to(
+            [*addr],
+            &ClientboundHandshakeSuccess {
+                compression_threshold,
+                encryption,
+            },
+        );
+
+        let Some(client) = self.connections.get_mut(addr) else {
+            return;
+        };
+        client
+            .connection
+            .set_compression_threshold(compression_threshold);
+
+        match exchange {
+            Some(exchange) => client.pending_exchange = Some(exchange),
+            None => self.transition(addr, ConnectionState::Handshake, ConnectionState::Login),
+        }
+    }
+
+    pub fn is_awaiting_key_exchange(&self, addr: &SocketAddr) -> bool {
+        self.connections
+            .get(addr)
+            .is_some_and(|client| client.pending_exchange.is_some())
+    }
+
+    /// Starts the encrypted session and moves the client to the login state.
+    pub fn complete_key_exchange(
+        &mut self,
+        addr: &SocketAddr,
+        client_key: &PublicKeyBytes,
+    ) -> Result<(), String> {
+        let client = self.connections.get_mut(addr).ok_or("Unknown connection")?;
+        let exchange = client
+            .pending_exchange
+            .take()
+            .ok_or("No key exchange was offered")?;
+        let session = exchange
+            .finish(client_key, Role::Server)
+            .ok_or("Invalid key exchange public key")?;
+
+        client.connection.start_session(session);
+        self.transition(addr, ConnectionState::Handshake, ConnectionState::Login);
+        Ok(())
+    }
+
+    /// Moves the client to the play state and opens its datagram channel,
+    /// returning the token the client must put in its datagrams.
+    pub fn accept_connection(
+        &mut self,
+        addr: SocketAddr,
+        profile: NetRemoteClient,
+    ) -> Option<SessionToken> {
+        self.transition(&addr, ConnectionState::Login, ConnectionState::Play);
+        let client = self
+            .connections
+            .get_mut(&addr)
+            .filter(|client| client.state == ConnectionState::Play)?;
+        client.remote = Some(profile);
+
+        let token = datagram::generate_token();
+        client.datagrams = Some(DatagramChannel::new(
+            token,
+            client.connection.datagram_cipher(),
+        ));
+        self.tokens.insert(token, addr);
+        Some(token)
+    }
+
+    /// Counts a packet the client was not allowed to send in its current state,
+    /// kicking the client once it did so too many times.
+    pub fn reject_packet(&mut self, addr: &SocketAddr, packet_name: &str) {
+        self.rejected_packets += 1;
+
+        let Some(client) = self.connections.get_mut(addr) else {
+            return;
+        };
+        client.violations += 1;
+
+        warn!(
+            "Rejected {packet_name} from client {addr} in the {:?} state ({}/{MAX_STATE_VIOLATIONS})",
+            client.state, client.violations
+        );
+
+        if client.violations >= MAX_STATE_VIOLATIONS {
+            let reason = format!("Sent {MAX_STATE_VIOLATIONS} packets in the wrong state");
+            self.kick(addr, &reason);
+        }
+    }
+
+    pub fn kick(&mut self, addr: &SocketAddr, reason: &str) {
+        let username = self.get_remote(addr).map(|client| client.username.clone());
+        warn!("Kicking client {addr} ({username:?}): {reason}");
+        self.disconnect_with(addr, DisconnectReason::Kicked(reason.to_string()));
+    }
+
+    /// Tells the client why it is disconnected before disconnecting it.
+    pub fn disconnect_with(&mut self, addr: &SocketAddr, reason: DisconnectReason) {
+        self.send_to([*addr], &ClientboundDisconnect { reason });
+        self.disconnect(addr);
+    }
+
+    /// Disconnects every client, the connections are closed by the next flushes.
+    pub fn shutdown(&mut self) {
+        let addrs = self.connections.keys().copied().collect::<Box<_>>();
+        for addr in addrs.iter() {
+            self.disconnect_with(addr, DisconnectReason::ServerShuttingDown);
+        }
+    }
+
+    pub fn has_connections(&self) -> bool {
+        !self.connections.is_empty()
+    }
+
+    pub fn is_username_taken(&self, username: &str) -> bool {
+        self.connections
+            .values()
+            .filter_map(|client| client.remote.as_ref())
+            .any(|remote| remote.username == username)
+    }
+
+    fn playing_clients(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.connections
+            .iter()
+            .filter(|(_, client)| client.state == ConnectionState::Play)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Logged in clients, with the entity they control.
+    pub fn players(&self) -> impl Iterator<Item = (SocketAddr, &NetRemoteClient)> + '_ {
+        self.connections
+            .iter()
+            .filter(|(_, client)| client.state == ConnectionState::Play)
+            .filter_map(|(addr, client)| Some((*addr, client.remote.as_ref()?)))
+    }
+
+    pub fn send_to<T: ClientboundPacket>(
         &mut self,
         addrs: impl IntoIterator<Item = SocketAddr>,
         packet: &T,
     ) {
-        let data = self.protocol.encode(packet);
-        self.send_data_to(addrs, data.into());
+        // Encode once per compression setting rather than once per client
+        let mut frames = HashMap::new();
+        let mut datagram_packet = None;
+        for addr in addrs {
+            let Some(client) = self.connections.get_mut(&addr) else {
+                continue;
+            };
+            if client.state == ConnectionState::Closing {
+                continue; // Disconnected earlier this update
+            }
+
+            // Until the client sent a datagram, we do not know where to send ours
+            let datagrams_open = !T::DELIVERY.is_reliable() && client.datagram_addr.is_some();
+            if let Some(channel) = client.datagrams.as_mut().filter(|_| datagrams_open) {
+                let encoded =
+                    datagram_packet.get_or_insert_with(|| self.protocol.encode_packet(packet));
+                if DatagramChannel::can_carry(encoded) {
+                    channel.send(encoded);
+                    continue;
+                }
+            }
+
+            let compression_threshold = client.connection.compression_threshold();
+            let frame = frames
+                .entry(compression_threshold)
+                .or_insert_with(|| self.protocol.encode(packet, compression_threshold));
+            client.connection.send(frame);
+
+            let pending = client.connection.pending_bytes();
+            if pending > self.max_send_backlog {
+                warn!("Client {addr} cannot keep up, {pending} bytes are waiting to be sent");
+                self.disconnect(&addr);
+            }
+        }
     }
 
-    pub fn broadcast<T: Serialize + 'static>(&mut self, packet: &T) {
-        let data = self.protocol.encode(packet);
-        let addrs = self.clients.keys().cloned().collect::<Box<_>>();
-        self.send_data_to(addrs, data.into());
+    pub fn broadcast<T: ClientboundPacket>(&mut self, packet: &T) {
+        let addrs = self.playing_clients().collect::<Box<_>>();
+        self.send_to(addrs.iter().copied(), packet);
     }
 
-    pub fn broadcast_except<T: Serialize + 'static>(&mut self, except: &SocketAddr, packet: &T) {
-        let data = self.protocol.encode(packet);
+    pub fn broadcast_except<T: ClientboundPacket>(&mut self, except: &SocketAddr, packet: &T) {
         let addrs = self
-            .clients
-            .keys()
-            .cloned()
+            .playing_clients()
             .filter(|a| a != except)
             .collect::<Box<_>>();
-        self.send_data_to(addrs, data.into());
+        self.send_to(addrs.iter().copied(), packet);
+    }
+
+    /// Traffic of every connection since the server started.
+    pub fn stats(&self) -> NetworkStats {
+        let mut stats = self.closed_stats;
+        for client in self.connections.values() {
+            stats.merge(&client.stats());
+        }
+        stats
     }
 
     pub fn get_remote(&self, addr: &SocketAddr) -> Option<&NetRemoteClient> {
-        self.clients.get(addr).map(|(_, client)| client)
+        self.connections.get(addr)?.remote.as_ref()
     }
 
     pub fn get_remote_mut(&mut self, addr: &SocketAddr) -> Option<&mut NetRemoteClient> {
-        self.clients.get_mut(addr).map(|(_, client)| client)
+        self.connections.get_mut(addr)?.remote.as_mut()
     }
 
+    /// Moves the connection to the closing state, it is dropped once its queued packets
+    /// are flushed.
     pub fn disconnect(&mut self, addr: &SocketAddr) {
-        self.connecting_clients.remove(addr);
-        if let Some((_, client)) = self.clients.remove(addr) {
-            self.disconnected_clients.insert(*addr, client);
+        let Some(client) = self.connections.get_mut(addr) else {
+            return;
+        };
+        if client.state == ConnectionState::Closing {
+            return;
+        }
+
+        client.state = ConnectionState::Closing;
+        client.closing_since = Some(Instant::now());
+        if let Some(channel) = client.datagrams.take() {
+            self.tokens.remove(&channel.token());
+            self.closed_stats.merge(channel.stats());
+        }
+        if let Some(remote) = client.remote.take() {
+            self.disconnected_clients.insert(*addr, remote);
         }
     }
 
     pub fn flush(&mut self) {
-        for (socket, _) in self.clients.values_mut() {
-            socket
-                .flush()
-                .unwrap_or_else(|e| warn!("Failed to flush stream to client: {e:?}"));
+        self.flush_datagrams();
+
+        let failed = self
+            .connections
+            .iter_mut()
+            .filter_map(|(addr, client)| client.connection.flush().err().map(|e| (*addr, e)))
+            .collect::<Box<_>>();
+
+        for (addr, e) in failed {
+            if self.connection_state(&addr) != Some(ConnectionState::Closing) {
+                warn!("Failed to flush stream to client {addr}: {e:?}");
+                self.disconnect(&addr);
+            }
+            if let Some(client) = self.connections.remove(&addr) {
+                self.closed_stats.merge(&client.stats());
+            }
+        }
+
+        let closed_stats = &mut self.closed_stats;
+        self.connections.retain(|_, client| {
+            let keep = match client.closing_since {
+                Some(since) => {
+                    client.connection.pending_bytes() > 0 && since.elapsed() < CLOSING_TIMEOUT
+                }
+                None => true,
+            };
+            if !keep {
+                closed_stats.merge(&client.stats());
+            }
+            keep
+        });
+    }
+
+    /// Datagrams that cannot be sent right away are dropped, like lost ones.
+    fn flush_datagrams(&mut self) {
+        let Some(socket) = &self.datagram_socket else {
+            return;
+        };
+
+        for (addr, client) in &mut self.connections {
+            let (Some(channel), Some(datagram_addr)) =
+                (&mut client.datagrams, client.datagram_addr)
+            else {
+                continue;
+            };
+
+            for datagram in channel.take_datagrams() {
+                match socket.send_to(&datagram, datagram_addr) {
+                    Ok(_) => {}
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!("Failed to send datagram to client {addr}: {e}");
+                        break;
+                    }
+                }
+            }
         }
     }
 }
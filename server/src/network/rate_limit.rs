@@ -0,0 +1,339 @@
+use std::{collections::HashMap, fmt, mem::size_of, time::Instant};
+
+use common::{
+    core::{
+        movement::{InputCommand, MAX_PENDING_INPUTS},
+        tick::TICK_RATE,
+    },
+    network::{
+        proto::{
+            extra::CommonPing,
+            play::{ServerboundBreakTile, ServerboundPlayerInputs, ServerboundSetTile},
+        },
+        Packet, PacketId,
+    },
+};
+
+/// Input packets an honest client sends per second, one per server tick.
+const INPUTS_PER_SECOND: f32 = TICK_RATE as f32;
+/// Largest input packet an honest client sends, carrying every pending input.
+const MAX_INPUTS_SIZE: f32 =
+    (size_of::<u64>() + MAX_PENDING_INPUTS * size_of::<InputCommand>()) as f32;
+
+/// What happens to a packet going over a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitAction {
+    /// The packet is not handled
+    Drop,
+    /// The packet is handled anyway, only logged
+    Warn,
+    /// The client is disconnected
+    Kick,
+}
+
+/// Sustained rate and burst allowed by a token bucket.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f32,
+    pub burst: f32,
+    pub action: LimitAction,
+}
+
+impl RateLimit {
+    pub const fn new(per_second: f32, burst: f32, action: LimitAction) -> Self {
+        Self {
+            per_second,
+            burst,
+            action,
+        }
+    }
+}
+
+/// Limits applied to every connection, and to the server as a whole.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// By packet type, packets without a limit are only counted by `packets`
+    pub per_packet: HashMap<PacketId, RateLimit>,
+    /// Packets of any type
+    pub packets: RateLimit,
+    /// Packet bytes of any type
+    pub bytes: RateLimit,
+    /// Packet bytes of every connection together, so many clients cannot flood the server
+    pub server_bytes: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            per_packet: HashMap::from([
+                (
+                    ServerboundPlayerInputs::ID,
+                    RateLimit::new(
+                        2. * INPUTS_PER_SECOND,
+                        4. * INPUTS_PER_SECOND,
+                        LimitAction::Drop,
+                    ),
+                ),
+                (CommonPing::ID, RateLimit::new(10., 20., LimitAction::Drop)),
+                (
+                    ServerboundSetTile::ID,
+                    RateLimit::new(20., 40., LimitAction::Drop),
+                ),
+                (
+                    ServerboundBreakTile::ID,
+                    RateLimit::new(20., 40., LimitAction::Drop),
+                ),
+            ]),
+            // Far above what a client sends, the packet types above are limited first
+            packets: RateLimit::new(
+                10. * INPUTS_PER_SECOND,
+                20. * INPUTS_PER_SECOND,
+                LimitAction::Kick,
+            ),
+            bytes: RateLimit::new(
+                4. * INPUTS_PER_SECOND * MAX_INPUTS_SIZE,
+                8. * INPUTS_PER_SECOND * MAX_INPUTS_SIZE,
+                LimitAction::Kick,
+            ),
+            // The client going over it is not to blame for the others
+            server_bytes: RateLimit::new(4. * 1024. * 1024., 8. * 1024. * 1024., LimitAction::Drop),
+        }
+    }
+}
+
+/// How often limits were exceeded, by action taken.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct RateLimitCounters {
+    pub dropped: u64,
+    pub warned: u64,
+    pub kicked: u64,
+    /// Packets that went over the server-wide limit, also counted by action
+    pub server_limited: u64,
+}
+
+impl RateLimitCounters {
+    pub fn record(&mut self, action: LimitAction) {
+        match action {
+            LimitAction::Drop => self.dropped += 1,
+            LimitAction::Warn => self.warned += 1,
+            LimitAction::Kick => self.kicked += 1,
+        }
+    }
+}
+
+impl fmt::Display for RateLimitCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Rate limits: dropped {} packets, warned {} times, kicked {} clients ({} packets over the server-wide limit)",
+            self.dropped, self.warned, self.kicked, self.server_limited,
+        )
+    }
+}
+
+struct TokenBucket {
+    tokens: f32,
+    refilled: Instant,
+    /// Whether the last take failed, so a flood is only logged when it starts
+    exceeded: bool,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimit) -> Self {
+        Self {
+            tokens: limit.burst,
+            refilled: Instant::now(),
+            exceeded: false,
+        }
+    }
+
+    fn try_take(&mut self, limit: &RateLimit, amount: f32) -> bool {
+        let elapsed = self.refilled.elapsed().as_secs_f32();
+        self.refilled = Instant::now();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst);
+
+        if self.tokens < amount {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// A limit a packet went over.
+pub struct Violation {
+    pub limit: &'static str,
+    pub action: LimitAction,
+    /// False while the client keeps going over the same limit
+    pub first: bool,
+}
+
+/// Token buckets of one connection.
+pub struct RateLimiter {
+    per_packet: HashMap<PacketId, TokenBucket>,
+    packets: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        Self {
+            per_packet: HashMap::new(),
+            packets: TokenBucket::new(&limits.packets),
+            bytes: TokenBucket::new(&limits.bytes),
+        }
+    }
+
+    /// Takes a packet of `size` bytes from the buckets, returning the most severe limit
+    /// it went over.
+    pub fn check(&mut self, limits: &RateLimits, id: PacketId, size: usize) -> Option<Violation> {
+        let per_packet = limits.per_packet.get(&id).map(|limit| {
+            let bucket = self
+                .per_packet
+                .entry(id)
+                .or_insert_with(|| TokenBucket::new(limit));
+            ("packet type", limit, bucket)
+        });
+
+        let mut worst: Option<Violation> = None;
+        for (name, limit, bucket, amount) in per_packet
+            .map(|(name, limit, bucket)| (name, limit, bucket, 1.))
+            .into_iter()
+            .chain([
+                ("packets", &limits.packets, &mut self.packets, 1.),
+                ("bytes", &limits.bytes, &mut self.bytes, size as f32),
+            ])
+        {
+            let exceeded = !bucket.try_take(limit, amount);
+            let first = exceeded && !bucket.exceeded;
+            bucket.exceeded = exceeded;
+
+            if exceeded
+                && worst
+                    .as_ref()
+                    .is_none_or(|w| severity(limit.action) > severity(w.action))
+            {
+                worst = Some(Violation {
+                    limit: name,
+                    action: limit.action,
+                    first,
+                });
+            }
+        }
+        worst
+    }
+}
+
+/// Token bucket shared by every connection.
+pub struct ServerRateLimiter {
+    bytes: TokenBucket,
+}
+
+impl ServerRateLimiter {
+    pub fn new(limits: &RateLimits) -> Self {
+        Self {
+            bytes: TokenBucket::new(&limits.server_bytes),
+        }
+    }
+
+    /// Takes a packet of `size` bytes from the bucket, returning the limit it went over.
+    pub fn check(&mut self, limits: &RateLimits, size: usize) -> Option<Violation> {
+        let exceeded = !self.bytes.try_take(&limits.server_bytes, size as f32);
+        let first = exceeded && !self.bytes.exceeded;
+        self.bytes.exceeded = exceeded;
+
+        exceeded.then_some(Violation {
+            limit: "server bytes",
+            action: limits.server_bytes.action,
+            first,
+        })
+    }
+}
+
+fn severity(action: LimitAction) -> u8 {
+    match action {
+        LimitAction::Warn => 0,
+        LimitAction::Drop => 1,
+        LimitAction::Kick => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Limits that never refill, so only the bursts matter.
+    fn limits(bytes: f32, server_bytes: f32) -> RateLimits {
+        RateLimits {
+            per_packet: HashMap::new(),
+            packets: RateLimit::new(0., 1000., LimitAction::Kick),
+            bytes: RateLimit::new(0., bytes, LimitAction::Kick),
+            server_bytes: RateLimit::new(0., server_bytes, LimitAction::Drop),
+        }
+    }
+
+    #[test]
+    fn bucket_allows_burst_then_refills() {
+        let limit = RateLimit::new(10., 2., LimitAction::Drop);
+        let mut bucket = TokenBucket::new(&limit);
+        assert!(bucket.try_take(&limit, 1.));
+        assert!(bucket.try_take(&limit, 1.));
+        assert!(!bucket.try_take(&limit, 1.));
+
+        // A second refills 10 tokens, but no more than the burst is kept
+        bucket.refilled -= Duration::from_secs(1);
+        assert!(bucket.try_take(&limit, 1.));
+        assert!(bucket.try_take(&limit, 1.));
+        assert!(!bucket.try_take(&limit, 1.));
+    }
+
+    #[test]
+    fn server_budget_is_shared_by_connections() {
+        let limits = limits(100., 150.);
+        let mut server = ServerRateLimiter::new(&limits);
+        let mut a = RateLimiter::new(&limits);
+        let mut b = RateLimiter::new(&limits);
+
+        assert!(a.check(&limits, 0, 100).is_none());
+        assert!(server.check(&limits, 100).is_none());
+
+        // Within its own budget, but not within what is left of the server's
+        assert!(b.check(&limits, 0, 100).is_none());
+        let violation = server.check(&limits, 100).unwrap();
+        assert_eq!(violation.limit, "server bytes");
+        assert_eq!(violation.action, LimitAction::Drop);
+
+        let violation = a.check(&limits, 0, 1).unwrap();
+        assert_eq!(violation.limit, "bytes");
+        assert_eq!(violation.action, LimitAction::Kick);
+    }
+
+    #[test]
+    fn reports_most_severe_violation_once() {
+        let mut limits = limits(1000., 1000.);
+        limits
+            .per_packet
+            .insert(0, RateLimit::new(0., 1., LimitAction::Drop));
+        limits.packets = RateLimit::new(0., 2., LimitAction::Kick);
+        let mut limiter = RateLimiter::new(&limits);
+
+        assert!(limiter.check(&limits, 0, 1).is_none());
+
+        let violation = limiter.check(&limits, 0, 1).unwrap();
+        assert_eq!(violation.limit, "packet type");
+        assert_eq!(violation.action, LimitAction::Drop);
+        assert!(violation.first);
+
+        // Both limits are exceeded, kicking wins over dropping
+        let violation = limiter.check(&limits, 0, 1).unwrap();
+        assert_eq!(violation.limit, "packets");
+        assert_eq!(violation.action, LimitAction::Kick);
+        assert!(violation.first);
+
+        let violation = limiter.check(&limits, 0, 1).unwrap();
+        assert_eq!(violation.action, LimitAction::Kick);
+        assert!(!violation.first);
+    }
+}
@@ -2,10 +2,18 @@ use std::time::Instant;
 
 use ecs::EntityId;
 
+use crate::streaming::ChunkView;
+
+/// Movement time a client may save up, covering inputs delayed by network jitter.
+const MAX_MOVEMENT_BUDGET: f32 = 0.5;
+
 pub struct NetRemoteClient {
     pub username: String,
-    pub last_packet: Instant,
     pub entity: EntityId,
+    /// Sequence number of the last simulated input
+    pub last_input: u32,
+    pub movement_budget: MovementBudget,
+    pub chunks: ChunkView,
 }
 
 impl NetRemoteClient {
@@ -13,7 +21,39 @@ impl NetRemoteClient {
         Self {
             username,
             entity,
-            last_packet: Instant::now(),
+            last_input: 0,
+            movement_budget: MovementBudget::new(),
+            chunks: ChunkView::new(),
+        }
+    }
+}
+
+/// Keeps the simulated movement time under the real time elapsed,
+/// so sending more inputs does not make a player faster.
+pub struct MovementBudget {
+    available: f32,
+    refilled: Instant,
+}
+
+impl MovementBudget {
+    pub fn new() -> Self {
+        Self {
+            available: 0.,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Returns how much of `dt` may be simulated.
+    pub fn spend(&mut self, dt: f32) -> f32 {
+        self.available =
+            (self.available + self.refilled.elapsed().as_secs_f32()).min(MAX_MOVEMENT_BUDGET);
+        self.refilled = Instant::now();
+
+        if !dt.is_finite() {
+            return 0.;
         }
+        let spent = dt.clamp(0., self.available);
+        self.available -= spent;
+        spent
     }
 }
@@ -0,0 +1,114 @@
+use common::network::replication::{
+    replicated_components, ComponentData, EntitySnapshot, NetworkEntity, ReplicatedComponents,
+    ReplicationPolicy,
+};
+use ecs::{Entities, Entity, EntityHandle, Query};
+
+/// Replication state of a server entity, what its clients last received.
+pub struct Replicated {
+    pub id: NetworkEntity,
+    /// Last value sent for each component, by component id
+    sent: Vec<Option<Box<[u8]>>>,
+    /// Whether each component changed on the last tick
+    changing: Vec<bool>,
+}
+
+/// Tracks the replicated components of entities and what changed between ticks.
+pub struct Replicator {
+    components: ReplicatedComponents,
+    next_id: u32,
+    tick: u64,
+}
+
+impl Replicator {
+    pub fn new() -> Self {
+        Self {
+            components: replicated_components(),
+            next_id: 0,
+            tick: 0,
+        }
+    }
+
+    /// Starts replicating an entity, returning the snapshot clients spawn it from.
+    pub fn track(&mut self, entity: &mut EntityHandle) -> EntitySnapshot {
+        let id = NetworkEntity(self.next_id);
+        self.next_id += 1;
+
+        let snapshot = self.components.snapshot(id, entity);
+        let mut sent = vec![None; self.components.ids().count()];
+        for ComponentData { component, data } in &snapshot.components {
+            sent[*component as usize] = Some(data.clone());
+        }
+
+        entity.set(Replicated {
+            id,
+            changing: vec![false; sent.len()],
+            sent,
+        });
+        snapshot
+    }
+
+    pub fn network_id(&self, entity: &EntityHandle) -> Option<NetworkEntity> {
+        entity.get::<Replicated>().map(|replicated| replicated.id)
+    }
+
+    /// Every replicated component of a tracked entity, for clients starting to see it.
+    pub fn snapshot(&self, entity: &EntityHandle) -> Option<EntitySnapshot> {
+        let network_id = self.network_id(entity)?;
+        Some(self.components.snapshot(network_id, entity))
+    }
+
+    /// Advances one tick, returning the components to send since the last one.
+    pub fn collect_changes(&mut self, entities: &mut Entities) -> Vec<EntitySnapshot> {
+        self.tick += 1;
+
+        let ids = entities
+            .with::<Replicated>()
+            .iter()
+            .map(|e| e.id())
+            .collect::<Vec<_>>();
+
+        let mut changes = Vec::new();
+        for id in ids {
+            let Some(entity) = entities.edit(id) else {
+                continue;
+            };
+            let mut replicated = entity.get_mut::<Replicated>().unwrap();
+
+            let mut components = Vec::new();
+            for component in self.components.ids() {
+                let index = component as usize;
+                let value = match self.components.policy(component) {
+                    ReplicationPolicy::OnSpawn => None,
+                    ReplicationPolicy::OnChange => {
+                        let value = self.components.encode(component, &entity);
+                        let current = value.as_ref().map(|value| &value.data);
+                        let changed = current != replicated.sent[index].as_ref();
+                        let settled = !changed && replicated.changing[index];
+                        replicated.changing[index] = changed;
+                        value.filter(|_| changed || settled)
+                    }
+                    ReplicationPolicy::EveryTicks(n)
+                        if self.tick.is_multiple_of(n.max(1) as u64) =>
+                    {
+                        self.components.encode(component, &entity)
+                    }
+                    ReplicationPolicy::EveryTicks(_) => None,
+                };
+
+                if let Some(value) = value {
+                    replicated.sent[index] = Some(value.data.clone());
+                    components.push(value);
+                }
+            }
+
+            if !components.is_empty() {
+                changes.push(EntitySnapshot {
+                    entity: replicated.id,
+                    components,
+                });
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,204 @@
+pub mod region;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use cgmath::Vector2;
+use common::{
+    core::tick::Tick,
+    logger::warn,
+    tilemap::{tile::Tile, ChunkCoord, TileChunk, CHUNK_SIZE},
+    utils::registry::{RecordId, Registry},
+};
+use region::RegionCoord;
+use serde::{Deserialize, Serialize};
+
+const LEVEL_FILE: &str = "level.json";
+const REGIONS_DIR: &str = "regions";
+
+/// What a saved world needs besides its chunks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LevelData {
+    pub seed: u64,
+    pub spawn: Vector2<f32>,
+    /// Tick the server was at when saved, so time carries on where it stopped
+    pub tick: Tick,
+}
+
+impl LevelData {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            spawn: Vector2::new(0., 0.),
+            tick: Tick::ZERO,
+        }
+    }
+}
+
+/// A chunk as written in its region, with the labels of its tiles rather than their ids
+/// which change as tiles are added.
+#[derive(Serialize, Deserialize)]
+struct SavedChunk {
+    palette: Vec<String>,
+    /// Indices in the palette, row by row
+    tiles: Vec<u16>,
+}
+
+fn invalid(reason: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason.into())
+}
+
+/// A world directory, holding the level data and the regions its chunks are grouped in.
+pub struct WorldSave {
+    dir: PathBuf,
+    labels: Vec<String>,
+    ids: HashMap<String, RecordId>,
+    /// Regions already reported as corrupted
+    reported: HashSet<RegionCoord>,
+}
+
+impl WorldSave {
+    /// Creates the directory if there is no world there yet.
+    pub fn open(dir: impl Into<PathBuf>, tiles: &Registry<Tile>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(REGIONS_DIR))?;
+
+        let labels = tiles.entries.keys().cloned().collect::<Vec<_>>();
+        let ids = labels
+            .iter()
+            .enumerate()
+            .map(|(id, label)| (label.clone(), RecordId(id)))
+            .collect();
+        Ok(Self {
+            dir,
+            labels,
+            ids,
+            reported: HashSet::new(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn regions(&self) -> PathBuf {
+        self.dir.join(REGIONS_DIR)
+    }
+
+    /// `None` for a new world.
+    pub fn load_level(&self) -> io::Result<Option<LevelData>> {
+        let raw = match fs::read_to_string(self.dir.join(LEVEL_FILE)) {
+            Ok(raw) => raw,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&raw)
+            .map(Some)
+            .map_err(|e| invalid(format!("corrupted {LEVEL_FILE}: {e}")))
+    }
+
+    pub fn save_level(&self, level: &LevelData) -> io::Result<()> {
+        let raw = serde_json::to_string_pretty(level).expect("Failed to serialize level data");
+        let path = self.dir.join(LEVEL_FILE);
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, raw)?;
+        fs::rename(&temp, &path)
+    }
+
+    /// `None` if the chunk was never saved. Errors of kind [`ErrorKind::InvalidData`]
+    /// mean the chunk or its region is corrupted.
+    pub fn load_chunk(&self, coord: ChunkCoord) -> io::Result<Option<TileChunk>> {
+        let Some(data) = region::read_chunk(&self.regions(), coord)? else {
+            return Ok(None);
+        };
+        let saved =
+            bincode::deserialize::<SavedChunk>(&data).map_err(|e| invalid(e.to_string()))?;
+
+        let palette = saved
+            .palette
+            .iter()
+            .map(|label| {
+                self.ids
+                    .get(label)
+                    .copied()
+                    .ok_or_else(|| invalid(format!("unknown tile {label:?}")))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        if saved.tiles.len() != (CHUNK_SIZE.x * CHUNK_SIZE.y) as usize {
+            return Err(invalid("wrong number of tiles"));
+        }
+        let mut chunk = TileChunk::new_filled(RecordId(0));
+        for (tile, index) in chunk.tiles.iter_mut().flatten().zip(saved.tiles) {
+            *tile = *palette
+                .get(index as usize)
+                .ok_or_else(|| invalid("tile outside of the palette"))?;
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Warns about a corrupted chunk, only once for each region.
+    pub fn report_corruption(&mut self, coord: ChunkCoord, error: &io::Error) {
+        let region = RegionCoord::of(coord);
+        if self.reported.insert(region) {
+            warn!(
+                "Region {} {} of {} is corrupted, its damaged chunks are generated again: {error}",
+                region.0,
+                region.1,
+                self.dir.display()
+            );
+        }
+    }
+
+    fn encode(&self, chunk: &TileChunk) -> Vec<u8> {
+        let mut palette = Vec::<RecordId>::new();
+        let tiles = chunk
+            .tiles
+            .iter()
+            .flatten()
+            .map(|tile| {
+                let index = palette.iter().position(|t| t == tile).unwrap_or_else(|| {
+                    palette.push(*tile);
+                    palette.len() - 1
+                });
+                index as u16
+            })
+            .collect();
+
+        let saved = SavedChunk {
+            palette: palette
+                .into_iter()
+                .map(|id| self.labels[id.0].clone())
+                .collect(),
+            tiles,
+        };
+        bincode::serialize(&saved).expect("Failed to serialize chunk")
+    }
+
+    /// Writes the chunks in their regions, returning how many regions were written.
+    pub fn save_chunks<'a>(
+        &self,
+        chunks: impl IntoIterator<Item = (ChunkCoord, &'a TileChunk)>,
+    ) -> io::Result<usize> {
+        let regions = region::by_region(chunks);
+        let dir = self.regions();
+        for (region, chunks) in &regions {
+            let encoded = chunks
+                .iter()
+                .map(|(coord, chunk)| (*coord, self.encode(chunk)));
+            if let Some(damage) = region::write_chunks(&dir, *region, encoded)? {
+                warn!(
+                    "Region {} {} of {} was corrupted, its damaged chunks were dropped: {damage}",
+                    region.0,
+                    region.1,
+                    self.dir.display()
+                );
+            }
+        }
+        Ok(regions.len())
+    }
+}
@@ -0,0 +1,327 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use common::tilemap::ChunkCoord;
+
+/// Side of a region in chunks.
+pub const REGION_SIZE: i32 = 32;
+const REGION_CHUNKS: usize = (REGION_SIZE * REGION_SIZE) as usize;
+
+/// Leads every region file, followed by the format version.
+const MAGIC: &[u8; 4] = b"UWRG";
+const VERSION: u32 = 1;
+/// Offset, length and checksum of a chunk, an offset of zero means it was never saved.
+const ENTRY_SIZE: usize = 12;
+const HEADER_SIZE: usize = MAGIC.len() + 4 + REGION_CHUNKS * ENTRY_SIZE;
+
+/// Coordinates of the region a chunk is stored in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RegionCoord(pub i32, pub i32);
+
+impl RegionCoord {
+    pub fn of(chunk: ChunkCoord) -> Self {
+        Self(
+            chunk.0.x.div_euclid(REGION_SIZE),
+            chunk.0.y.div_euclid(REGION_SIZE),
+        )
+    }
+
+    pub fn path(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("r.{}.{}.region", self.0, self.1))
+    }
+}
+
+/// Index of a chunk in the offset table of its region.
+fn chunk_index(chunk: ChunkCoord) -> usize {
+    let local = chunk.0.map(|i| i.rem_euclid(REGION_SIZE));
+    (local.y * REGION_SIZE + local.x) as usize
+}
+
+/// FNV-1a, enough to notice a chunk was damaged on disk.
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn invalid(reason: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, reason.into())
+}
+
+/// Saved data of the chunks of a region, by index in its offset table.
+type RegionChunks = BTreeMap<usize, Vec<u8>>;
+
+#[derive(Clone, Copy)]
+struct Entry {
+    offset: u32,
+    length: u32,
+    checksum: u32,
+}
+
+/// Reads and checks the header, returning the offset table.
+/// Entries pointing outside of the file are only noticed once read.
+fn read_table(file: &mut File) -> io::Result<Vec<Entry>> {
+    let mut header = vec![0; HEADER_SIZE];
+    file.read_exact(&mut header)
+        .map_err(|e| invalid(format!("truncated header: {e}")))?;
+
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a region file"));
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(invalid(format!("unsupported region version {version}")));
+    }
+
+    let table = header[8..]
+        .chunks_exact(ENTRY_SIZE)
+        .map(|entry| {
+            let field = |i: usize| u32::from_le_bytes(entry[i * 4..i * 4 + 4].try_into().unwrap());
+            Entry {
+                offset: field(0),
+                length: field(1),
+                checksum: field(2),
+            }
+        })
+        .collect();
+    Ok(table)
+}
+
+/// Entries are checked one by one, so a damaged one does not hide the others.
+fn read_entry(file: &mut File, file_size: u64, entry: Entry) -> io::Result<Vec<u8>> {
+    if (entry.offset as usize) < HEADER_SIZE
+        || entry.offset as u64 + entry.length as u64 > file_size
+    {
+        return Err(invalid("chunk outside of the file"));
+    }
+
+    let mut data = vec![0; entry.length as usize];
+    file.seek(SeekFrom::Start(entry.offset as u64))?;
+    file.read_exact(&mut data)?;
+    if checksum(&data) != entry.checksum {
+        return Err(invalid("chunk checksum mismatch"));
+    }
+    Ok(data)
+}
+
+/// Reads the saved data of a chunk, `None` if it was never saved.
+pub fn read_chunk(dir: &Path, chunk: ChunkCoord) -> io::Result<Option<Vec<u8>>> {
+    let mut file = match File::open(RegionCoord::of(chunk).path(dir)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let entry = read_table(&mut file)?[chunk_index(chunk)];
+    if entry.offset == 0 {
+        return Ok(None);
+    }
+    let file_size = file.metadata()?.len();
+    read_entry(&mut file, file_size, entry).map(Some)
+}
+
+/// Reads every intact chunk saved in a region by index, with the first damage found.
+fn read_region(path: &Path) -> io::Result<(RegionChunks, Option<io::Error>)> {
+    let mut chunks = BTreeMap::new();
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok((chunks, None)),
+        Err(e) => return Err(e),
+    };
+
+    let table = match read_table(&mut file) {
+        Ok(table) => table,
+        Err(e) if e.kind() == ErrorKind::InvalidData => return Ok((chunks, Some(e))),
+        Err(e) => return Err(e),
+    };
+    let file_size = file.metadata()?.len();
+    let mut damage = None;
+    for (index, entry) in table.into_iter().enumerate() {
+        if entry.offset == 0 {
+            continue;
+        }
+        match read_entry(&mut file, file_size, entry) {
+            Ok(data) => {
+                chunks.insert(index, data);
+            }
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                damage.get_or_insert(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((chunks, damage))
+}
+
+/// Rewrites a region with the given chunks replacing the saved ones.
+/// Damaged chunks of the region are dropped, the damage is returned so it can be reported.
+pub fn write_chunks(
+    dir: &Path,
+    region: RegionCoord,
+    chunks: impl IntoIterator<Item = (ChunkCoord, Vec<u8>)>,
+) -> io::Result<Option<io::Error>> {
+    let path = region.path(dir);
+    let (mut saved, damage) = read_region(&path)?;
+    for (chunk, data) in chunks {
+        debug_assert_eq!(RegionCoord::of(chunk), region);
+        saved.insert(chunk_index(chunk), data);
+    }
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    let mut body = Vec::new();
+    for index in 0..REGION_CHUNKS {
+        let entry = match saved.get(&index) {
+            Some(data) => {
+                let entry = Entry {
+                    offset: (HEADER_SIZE + body.len()) as u32,
+                    length: data.len() as u32,
+                    checksum: checksum(data),
+                };
+                body.extend_from_slice(data);
+                entry
+            }
+            None => Entry {
+                offset: 0,
+                length: 0,
+                checksum: 0,
+            },
+        };
+        header.extend_from_slice(&entry.offset.to_le_bytes());
+        header.extend_from_slice(&entry.length.to_le_bytes());
+        header.extend_from_slice(&entry.checksum.to_le_bytes());
+    }
+
+    // Written next to the region first, so a crash mid-write leaves the previous one intact
+    let temp = path.with_extension("region.tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(&header)?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    fs::rename(&temp, &path)?;
+
+    Ok(damage)
+}
+
+/// Groups chunks by the region they are stored in.
+pub fn by_region<T>(
+    chunks: impl IntoIterator<Item = (ChunkCoord, T)>,
+) -> BTreeMap<RegionCoord, Vec<(ChunkCoord, T)>> {
+    let mut regions = BTreeMap::<_, Vec<_>>::new();
+    for (coord, chunk) in chunks {
+        regions
+            .entry(RegionCoord::of(coord))
+            .or_default()
+            .push((coord, chunk));
+    }
+    regions
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector2;
+
+    use super::*;
+
+    /// An empty directory only used by one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("underworld-region-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn chunk(x: i32, y: i32) -> ChunkCoord {
+        ChunkCoord(Vector2::new(x, y))
+    }
+
+    /// Writes two chunks of the same region, returning where the region is.
+    fn write_two(dir: &Path) -> PathBuf {
+        let region = RegionCoord::of(chunk(1, 2));
+        let chunks = [
+            (chunk(1, 2), b"first".to_vec()),
+            (chunk(3, 4), b"second".to_vec()),
+        ];
+        assert!(write_chunks(dir, region, chunks).unwrap().is_none());
+        region.path(dir)
+    }
+
+    /// Position of the entry of a chunk in its region file.
+    fn entry_position(chunk: ChunkCoord) -> usize {
+        MAGIC.len() + 4 + chunk_index(chunk) * ENTRY_SIZE
+    }
+
+    #[test]
+    fn round_trips_chunks() {
+        let dir = test_dir("round-trip");
+        write_two(&dir);
+        assert_eq!(read_chunk(&dir, chunk(1, 2)).unwrap().unwrap(), b"first");
+        assert_eq!(read_chunk(&dir, chunk(3, 4)).unwrap().unwrap(), b"second");
+        assert!(read_chunk(&dir, chunk(5, 6)).unwrap().is_none());
+        assert!(read_chunk(&dir, chunk(-1, -1)).unwrap().is_none());
+
+        // Chunks not written again are kept
+        let region = RegionCoord::of(chunk(1, 2));
+        write_chunks(&dir, region, [(chunk(1, 2), b"changed".to_vec())]).unwrap();
+        assert_eq!(read_chunk(&dir, chunk(1, 2)).unwrap().unwrap(), b"changed");
+        assert_eq!(read_chunk(&dir, chunk(3, 4)).unwrap().unwrap(), b"second");
+
+        let region = RegionCoord::of(chunk(-1, -1));
+        write_chunks(&dir, region, [(chunk(-1, -1), b"negative".to_vec())]).unwrap();
+        assert_eq!(
+            read_chunk(&dir, chunk(-1, -1)).unwrap().unwrap(),
+            b"negative"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entry_only_loses_its_chunk() {
+        let dir = test_dir("corrupt-entry");
+        let path = write_two(&dir);
+
+        let mut data = fs::read(&path).unwrap();
+        let position = entry_position(chunk(1, 2));
+        data[position..position + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        let error = read_chunk(&dir, chunk(1, 2)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(read_chunk(&dir, chunk(3, 4)).unwrap().unwrap(), b"second");
+
+        // Rewriting the region drops the damaged chunk and reports it
+        let region = RegionCoord::of(chunk(5, 6));
+        let damage = write_chunks(&dir, region, [(chunk(5, 6), b"third".to_vec())]).unwrap();
+        assert_eq!(damage.unwrap().kind(), ErrorKind::InvalidData);
+        assert!(read_chunk(&dir, chunk(1, 2)).unwrap().is_none());
+        assert_eq!(read_chunk(&dir, chunk(3, 4)).unwrap().unwrap(), b"second");
+        assert_eq!(read_chunk(&dir, chunk(5, 6)).unwrap().unwrap(), b"third");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn detects_bad_checksum() {
+        let dir = test_dir("bad-checksum");
+        let path = write_two(&dir);
+
+        let mut data = fs::read(&path).unwrap();
+        let position = entry_position(chunk(3, 4));
+        let offset = u32::from_le_bytes(data[position..position + 4].try_into().unwrap());
+        data[offset as usize] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let error = read_chunk(&dir, chunk(3, 4)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(read_chunk(&dir, chunk(1, 2)).unwrap().unwrap(), b"first");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
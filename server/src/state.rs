@@ -1,39 +1,284 @@
-use std::net::SocketAddr;
+use std::{io, net::SocketAddr, path::PathBuf};
 
-use cgmath::Vector2;
+use cgmath::{InnerSpace, Vector2};
 use common::{
-    core::spatial::Position, network::proto::play::ClientboundSetEntityPosition, tilemap::TileMap,
+    core::{
+        movement::{self, InputCommand},
+        spatial::Position,
+        tick::Tick,
+    },
+    logger::info,
+    network::proto::play::{
+        ClientboundChunkData, ClientboundEntityDelta, ClientboundPlayerState,
+        ClientboundRemoveEntity, ClientboundSpawnEntity, ClientboundTileUpdate,
+        ClientboundUnloadChunk,
+    },
+    tilemap::{
+        generation::{self, WorldGenerator},
+        tile::MAX_REACH,
+        ChunkCoord,
+    },
+    utils::registry::RecordId,
 };
-use ecs::Entities;
+use ecs::{Entities, Entity, Query};
 
-use crate::{assets::ServerAssets, network::NetworkServer};
+use crate::{
+    assets::ServerAssets,
+    interest::{InterestManager, DEFAULT_VIEW_RADIUS},
+    network::NetworkServer,
+    replication::{Replicated, Replicator},
+    save::{LevelData, WorldSave},
+    streaming::DEFAULT_CHUNKS_PER_TICK,
+    world::{World, DEFAULT_LOAD_RADIUS},
+};
 
 pub struct ServerState {
-    pub terrain: TileMap,
+    pub world: World,
     pub entities: Entities,
+    pub replication: Replicator,
+    pub interest: InterestManager,
+    /// Chunks sent to each player per tick
+    pub chunks_per_tick: usize,
+    /// Where players appear when they log in
+    pub spawn: Vector2<f32>,
+    /// The tick being simulated
+    pub tick: Tick,
 }
 
 impl ServerState {
-    pub fn new(assets: &ServerAssets) -> Self {
+    /// A new world kept in memory only.
+    pub fn new(assets: &ServerAssets, seed: u64) -> Self {
+        Self::from_level(assets, LevelData::new(seed), None)
+    }
+
+    /// Loads the world saved in `dir`, or creates one there with a random seed.
+    pub fn open(assets: &ServerAssets, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let storage = WorldSave::open(dir, &assets.common.tiles)?;
+        let level = match storage.load_level()? {
+            Some(level) => {
+                info!("Loaded the world from {}", storage.dir().display());
+                level
+            }
+            None => {
+                info!("Creating a new world in {}", storage.dir().display());
+                LevelData::new(generation::random_seed())
+            }
+        };
+        Ok(Self::from_level(assets, level, Some(storage)))
+    }
+
+    fn from_level(assets: &ServerAssets, level: LevelData, storage: Option<WorldSave>) -> Self {
         Self {
-            terrain: TileMap::generate(Vector2::new(16, 16), assets.common.tiles.get_id("grass")),
+            world: World::new(
+                WorldGenerator::new(level.seed, &assets.common.tiles),
+                DEFAULT_LOAD_RADIUS,
+                storage,
+            ),
             entities: Entities::new(),
+            replication: Replicator::new(),
+            interest: InterestManager::new(DEFAULT_VIEW_RADIUS),
+            chunks_per_tick: DEFAULT_CHUNKS_PER_TICK,
+            spawn: level.spawn,
+            tick: level.tick,
+        }
+    }
+
+    pub fn level(&self) -> LevelData {
+        LevelData {
+            seed: self.world.generator.seed(),
+            spawn: self.spawn,
+            tick: self.tick,
+        }
+    }
+
+    /// Writes the level data and the changed chunks, if the world has a save.
+    pub fn save(&mut self) -> io::Result<()> {
+        let Some(storage) = self.world.storage() else {
+            return Ok(());
+        };
+        storage.save_level(&self.level())?;
+        let chunks = self.world.save()?;
+        info!("Saved the world, {chunks} chunks changed since the last save");
+        Ok(())
+    }
+
+    /// Loads the terrain around every player and unloads the chunks none of them is near.
+    pub fn update_world(&mut self, network: &NetworkServer) {
+        let positions = network
+            .players()
+            .filter_map(|(_, remote)| {
+                let entity = self.entities.edit(remote.entity)?;
+                let pos = entity.get::<Position>()?.0;
+                Some(pos)
+            })
+            .collect::<Vec<_>>();
+        self.world.update(positions);
+    }
+
+    /// Sends each player the loaded chunks around it that it lacks, nearest first,
+    /// and tells it to drop the ones it moved away from.
+    pub fn stream_chunks(&mut self, network: &mut NetworkServer) {
+        let players = network
+            .players()
+            .map(|(addr, remote)| (addr, remote.entity))
+            .collect::<Vec<_>>();
+
+        for (addr, player) in players {
+            let Some(pos) = self
+                .entities
+                .edit(player)
+                .and_then(|entity| Some(entity.get::<Position>()?.0))
+            else {
+                continue;
+            };
+            let Some(remote) = network.get_remote_mut(&addr) else {
+                continue;
+            };
+
+            let updates = remote.chunks.update(
+                ChunkCoord::from_position(pos),
+                &self.world,
+                self.chunks_per_tick,
+            );
+            for coord in updates.unload {
+                network.send_to([addr], &ClientboundUnloadChunk { coord });
+            }
+            for coord in updates.load {
+                let chunk = self.world.terrain.chunk(coord).unwrap().clone();
+                network.send_to([addr], &ClientboundChunkData { coord, chunk });
+            }
+        }
+    }
+
+    /// Changes a tile the player can reach and tells the players that have its chunk.
+    pub fn edit_tile(
+        &mut self,
+        addr: &SocketAddr,
+        pos: Vector2<i32>,
+        tile: RecordId,
+        network: &mut NetworkServer,
+    ) -> Result<(), &'static str> {
+        let player = network
+            .get_remote(addr)
+            .and_then(|remote| self.entities.edit(remote.entity))
+            .and_then(|entity| Some(entity.get::<Position>()?.0))
+            .ok_or("the player has no entity")?;
+        if (pos.map(|i| i as f32) - player).magnitude() > MAX_REACH {
+            return Err("the tile is out of reach");
+        }
+
+        match self.world.terrain.tile(pos) {
+            None => return Err("the chunk is not loaded"),
+            Some(current) if current == tile => return Err("the tile is already there"),
+            Some(_) => {}
+        }
+        self.world.terrain.set_tile(pos, tile);
+
+        let (coord, _) = ChunkCoord::split_tile(pos);
+        let viewers = network
+            .players()
+            .filter(|(_, remote)| remote.chunks.has(coord))
+            .map(|(addr, _)| addr)
+            .collect::<Vec<_>>();
+        network.send_to(viewers, &ClientboundTileUpdate { pos, tile });
+        Ok(())
+    }
+
+    /// Spawns and removes entities as they enter and leave the view of each player,
+    /// then sends the components that changed since the last call to the players seeing them.
+    pub fn replicate(&mut self, network: &mut NetworkServer) {
+        let changes = self.replication.collect_changes(&mut self.entities);
+
+        let ids = self
+            .entities
+            .with::<Replicated>()
+            .iter()
+            .map(|e| e.id())
+            .collect::<Vec<_>>();
+        self.interest.rebuild_grid(ids.into_iter().filter_map(|id| {
+            let entity = self.entities.edit(id)?;
+            let network_id = self.replication.network_id(&entity)?;
+            let pos = entity.get::<Position>()?.0;
+            Some((network_id, id, pos))
+        }));
+
+        let players = network
+            .players()
+            .map(|(addr, remote)| (addr, remote.entity))
+            .collect::<Vec<_>>();
+        let tick = self.tick;
+        for (addr, player) in players {
+            let viewer = self.entities.edit(player).and_then(|entity| {
+                let pos = entity.get::<Position>()?.0;
+                Some((self.replication.network_id(&entity)?, pos))
+            });
+            let Some((own, pos)) = viewer else {
+                continue;
+            };
+
+            let view = self.interest.update_player(addr, own, pos);
+            for entity in view.left {
+                network.send_to([addr], &ClientboundRemoveEntity { entity });
+            }
+            for (_, id) in &view.entered {
+                let snapshot = self
+                    .entities
+                    .edit(*id)
+                    .and_then(|entity| self.replication.snapshot(&entity));
+                if let Some(snapshot) = snapshot {
+                    network.send_to([addr], &ClientboundSpawnEntity { snapshot });
+                }
+            }
+
+            // Entities that just entered were spawned with their current components
+            let entities = changes
+                .iter()
+                .filter(|change| self.interest.can_see(&addr, change.entity))
+                .filter(|change| view.entered.iter().all(|(e, _)| *e != change.entity))
+                .cloned()
+                .collect::<Vec<_>>();
+            if !entities.is_empty() {
+                network.send_to([addr], &ClientboundEntityDelta { tick, entities });
+            }
         }
     }
 
-    pub fn set_player_position(
+    /// Simulates the inputs the player has not sent before, then tells it where it ended up.
+    pub fn apply_player_inputs(
         &mut self,
         addr: &SocketAddr,
-        pos: Position,
+        inputs: &[InputCommand],
         network: &mut NetworkServer,
     ) {
-        let entity = network.get_remote(&addr).unwrap().entity;
-        self.entities.edit(entity).unwrap().set(pos);
-        network.broadcast_except(
-            &addr,
-            &ClientboundSetEntityPosition {
-                entity: entity.into(),
+        let Some(remote) = network.get_remote_mut(addr) else {
+            return;
+        };
+        let Some(mut player) = self.entities.edit(remote.entity) else {
+            return;
+        };
+
+        let mut pos = *player.get::<Position>().unwrap();
+        for command in inputs {
+            if command.sequence <= remote.last_input {
+                continue; // Already simulated
+            }
+            let dt = remote
+                .movement_budget
+                .spend(command.dt.min(movement::MAX_INPUT_DT));
+            movement::apply_movement(&mut pos, command.input, dt);
+            remote.last_input = command.sequence;
+        }
+        player.set(pos);
+
+        // Sent even when nothing moved, so the client can drop inputs we already had,
+        // other players see the move in the next delta
+        let last_input = remote.last_input;
+        network.send_to(
+            [*addr],
+            &ClientboundPlayerState {
+                tick: self.tick,
                 pos,
+                last_input,
             },
         );
     }
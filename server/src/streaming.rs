@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+
+use cgmath::Vector2;
+use common::tilemap::ChunkCoord;
+
+use crate::world::{World, UNLOAD_MARGIN};
+
+/// Chunks sent to each player per tick unless configured otherwise, the nearest first.
+pub const DEFAULT_CHUNKS_PER_TICK: usize = 4;
+
+pub struct ChunkUpdates {
+    /// Nearest first
+    pub load: Vec<ChunkCoord>,
+    pub unload: Vec<ChunkCoord>,
+}
+
+/// Chunks a player was sent and still has loaded.
+#[derive(Default)]
+pub struct ChunkView {
+    sent: HashSet<ChunkCoord>,
+}
+
+impl ChunkView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn has(&self, coord: ChunkCoord) -> bool {
+        self.sent.contains(&coord)
+    }
+
+    /// Picks up to `budget` loaded chunks around `center` the player does not have yet,
+    /// and the chunks it should drop because it moved away from them.
+    pub fn update(&mut self, center: ChunkCoord, world: &World, budget: usize) -> ChunkUpdates {
+        let keep_radius = world.load_radius + UNLOAD_MARGIN;
+        let unload = self
+            .sent
+            .iter()
+            .copied()
+            .filter(|coord| {
+                center.distance(*coord) > keep_radius || !world.terrain.is_loaded(*coord)
+            })
+            .collect::<Vec<_>>();
+        for coord in &unload {
+            self.sent.remove(coord);
+        }
+
+        let radius = world.load_radius;
+        let mut missing = (-radius..=radius)
+            .flat_map(|x| (-radius..=radius).map(move |y| (x, y)))
+            .map(|(x, y)| ChunkCoord(center.0 + Vector2::new(x, y)))
+            .filter(|coord| !self.sent.contains(coord) && world.terrain.is_loaded(*coord))
+            .collect::<Vec<_>>();
+        missing.sort_by_key(|coord| {
+            let delta = coord.0 - center.0;
+            delta.x * delta.x + delta.y * delta.y
+        });
+        missing.truncate(budget);
+        self.sent.extend(missing.iter().copied());
+
+        ChunkUpdates {
+            load: missing,
+            unload,
+        }
+    }
+}
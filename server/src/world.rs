@@ -0,0 +1,150 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind},
+};
+
+use cgmath::Vector2;
+use common::{
+    logger::{debug, warn},
+    tilemap::{generation::WorldGenerator, ChunkCoord, TileChunk, TileMap},
+};
+
+use crate::save::WorldSave;
+
+/// How far around players chunks are loaded unless configured otherwise, in chunks.
+pub const DEFAULT_LOAD_RADIUS: i32 = 4;
+/// Extra distance past the load radius before chunks are unloaded, so players walking
+/// along a chunk border do not load and unload the same chunks over and over.
+pub const UNLOAD_MARGIN: i32 = 2;
+
+/// The unbounded terrain, of which only the chunks around players are kept loaded.
+pub struct World {
+    pub terrain: TileMap,
+    pub generator: WorldGenerator,
+    /// Chunks within this distance of a player are loaded, in chunks
+    pub load_radius: i32,
+    /// Where changed chunks are saved, kept in memory without one
+    storage: Option<WorldSave>,
+    /// Chunks that changed before being unloaded and are not saved to storage yet
+    unsaved: HashMap<ChunkCoord, TileChunk>,
+}
+
+impl World {
+    pub fn new(generator: WorldGenerator, load_radius: i32, storage: Option<WorldSave>) -> Self {
+        Self {
+            terrain: TileMap::new(),
+            generator,
+            load_radius,
+            storage,
+            unsaved: HashMap::new(),
+        }
+    }
+
+    pub fn storage(&self) -> Option<&WorldSave> {
+        self.storage.as_ref()
+    }
+
+    /// Loads the chunk, from where it was saved or by generating it.
+    pub fn ensure_loaded(&mut self, coord: ChunkCoord) -> bool {
+        if self.terrain.is_loaded(coord) {
+            return false;
+        }
+
+        let mut chunk = self.unsaved.remove(&coord);
+        let mut corrupted = false;
+        if chunk.is_none() {
+            if let Some(storage) = &mut self.storage {
+                match storage.load_chunk(coord) {
+                    Ok(saved) => chunk = saved,
+                    Err(e) if e.kind() == ErrorKind::InvalidData => {
+                        storage.report_corruption(coord, &e);
+                        corrupted = true;
+                    }
+                    Err(e) => warn!("Failed to read chunk {:?}, generating it: {e}", coord.0),
+                }
+            }
+        }
+        let generator = &self.generator;
+        self.terrain.ensure_loaded(coord, |coord| {
+            chunk.unwrap_or_else(|| generator.generate_chunk(coord))
+        });
+
+        // Saved again so the damaged copy is replaced
+        if corrupted {
+            self.terrain.mark_dirty(coord);
+        }
+        true
+    }
+
+    /// Removes the chunk from the terrain, if it changed it is kept until the next save.
+    pub fn unload(&mut self, coord: ChunkCoord) {
+        if let Some((chunk, true)) = self.terrain.unload(coord) {
+            self.unsaved.insert(coord, chunk);
+        }
+    }
+
+    /// Writes the changed chunks to storage, loaded or not, returning how many were written.
+    pub fn save(&mut self) -> io::Result<usize> {
+        let Some(storage) = &self.storage else {
+            return Ok(0);
+        };
+
+        let dirty = self.terrain.dirty_chunks().collect::<Vec<_>>();
+        let chunks = dirty
+            .iter()
+            .filter_map(|coord| Some((*coord, self.terrain.chunk(*coord)?)))
+            .chain(self.unsaved.iter().map(|(coord, chunk)| (*coord, chunk)));
+        storage.save_chunks(chunks)?;
+
+        let saved = dirty.len() + self.unsaved.len();
+        self.unsaved.clear();
+        self.terrain.mark_clean();
+        Ok(saved)
+    }
+
+    /// Loads the chunks around each player and unloads those far from all of them.
+    pub fn update(&mut self, players: impl IntoIterator<Item = Vector2<f32>>) {
+        let centers = players
+            .into_iter()
+            .map(ChunkCoord::from_position)
+            .collect::<HashSet<_>>();
+
+        let mut loaded = 0;
+        for center in &centers {
+            for coord in self.chunks_around(*center) {
+                loaded += self.ensure_loaded(coord) as usize;
+            }
+        }
+
+        let keep_radius = self.load_radius + UNLOAD_MARGIN;
+        let far = self
+            .terrain
+            .loaded_chunks()
+            .map(|(coord, _)| coord)
+            .filter(|coord| {
+                centers
+                    .iter()
+                    .all(|center| center.distance(*coord) > keep_radius)
+            })
+            .collect::<Vec<_>>();
+        // Changed ones wait for the next save rather than rewriting their region every tick
+        for coord in &far {
+            self.unload(*coord);
+        }
+
+        if loaded > 0 || !far.is_empty() {
+            debug!(
+                "Loaded {loaded} chunks and unloaded {}, {} are in memory",
+                far.len(),
+                self.terrain.chunks.len()
+            );
+        }
+    }
+
+    fn chunks_around(&self, center: ChunkCoord) -> impl Iterator<Item = ChunkCoord> {
+        let radius = self.load_radius;
+        (-radius..=radius).flat_map(move |x| {
+            (-radius..=radius).map(move |y| ChunkCoord(center.0 + Vector2::new(x, y)))
+        })
+    }
+}